@@ -0,0 +1,59 @@
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+
+const WIDTH: usize = 1920;
+const HEIGHT: usize = 1080;
+
+fn apply_filter_safe(data: &[u8], output: &mut Vec<u8>, width: usize, height: usize) {
+    let stride = width * 3;
+    let size = width * height;
+    let mut plane1 = 0;
+    let mut plane2 = size >> 2;
+    let mut plane3 = size >> 1;
+    output.clear();
+    output.resize(height * stride, 0);
+    let mut output_pos = 0;
+    for _ in 0..height >> 1 {
+        for _ in 0..width >> 1 {
+            let b = 226 * data[plane1] as i8 as i32;
+            let g = -43 * data[plane1] as i8 as i32 - 89 * data[plane2] as i8 as i32;
+            let r = 179 * data[plane2] as i8 as i32;
+            for i in [0, 1, width, width + 1] {
+                let base = (data[plane3 + i] as i32) << 7;
+                let pos = output_pos + 3 * i;
+                output[pos] = ((base + b) >> 7).clamp(0, 255) as u8;
+                output[pos + 1] = ((base + g) >> 7).clamp(0, 255) as u8;
+                output[pos + 2] = ((base + r) >> 7).clamp(0, 255) as u8;
+            }
+            plane1 += 1;
+            plane2 += 1;
+            plane3 += 2;
+            output_pos += 6;
+        }
+        plane3 += width;
+        output_pos += stride;
+    }
+}
+
+fn apply_filter(c: &mut Criterion) {
+    let data = (0..WIDTH * HEIGHT * 3 / 2)
+        .map(|i| (i * 7 + i / WIDTH) as u8)
+        .collect::<Vec<_>>();
+    let mut output = vec![];
+    let mut safe = vec![];
+    cratri_unpac::apply_filter(&data, &mut output, WIDTH, HEIGHT);
+    apply_filter_safe(&data, &mut safe, WIDTH, HEIGHT);
+    assert_eq!(output, safe);
+    let mut group = c.benchmark_group("apply_filter");
+    group.bench_function("unsafe", |b| {
+        b.iter(|| cratri_unpac::apply_filter(black_box(&data), &mut output, WIDTH, HEIGHT))
+    });
+    group.bench_function("safe", |b| {
+        b.iter(|| apply_filter_safe(black_box(&data), &mut output, WIDTH, HEIGHT))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, apply_filter);
+criterion_main!(benches);
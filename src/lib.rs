@@ -0,0 +1,5514 @@
+#![feature(ptr_as_ref_unchecked)]
+
+use std::borrow::Cow;
+use std::cell::{RefCell, RefMut};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::env;
+use std::error::Error;
+use std::ffi::{CStr, OsStr};
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::ops::{BitXor, Deref, DerefMut};
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use encoding_rs::Encoding;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use image::{ImageBuffer, ImageFormat, Pixel, Rgb, Rgba, RgbaImage, imageops};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+#[derive(Default)]
+pub enum Command {
+    #[default]
+    Extract,
+    Cat {
+        pac: String,
+        entry: String,
+    },
+    Probe {
+        pac: String,
+    },
+    Diff {
+        a: String,
+        b: String,
+    },
+    Plan {
+        pac: String,
+        dir: String,
+    },
+    Repair {
+        pac: String,
+        dir: String,
+        out: String,
+    },
+    Deps {
+        pac: String,
+    },
+    Replace {
+        pac: String,
+        entry: String,
+        file: String,
+        out: String,
+    },
+}
+
+/// The order `--sort` processes and reports entries in within each PAC.
+#[derive(Clone, Copy, Default)]
+enum EntryOrder {
+    #[default]
+    Archive,
+    Name,
+    Offset,
+    Size,
+}
+
+#[derive(Clone, Copy, Default)]
+enum OutputFormat {
+    #[default]
+    Png,
+    Qoi,
+    Dds,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Qoi => "qoi",
+            OutputFormat::Dds => "dds",
+        }
+    }
+
+    /// Writes an uncompressed 32-bit DDS with R8G8B8A8 byte order
+    /// (`DXGI_FORMAT_R8G8B8A8_UNORM`), described by a legacy pixel format with
+    /// explicit channel masks and no mipmaps.
+    fn encode_dds(image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Vec<u8> {
+        let (width, height) = image.dimensions();
+        let mut data = b"DDS ".to_vec();
+        for field in [124, 0x100f, height, width, width * 4, 0, 0]
+            .into_iter()
+            .chain([0; 11])
+            .chain([
+                32,
+                0x41,
+                0,
+                32,
+                0x0000_00ff,
+                0x0000_ff00,
+                0x00ff_0000,
+                0xff00_0000,
+            ])
+            .chain([0x1000, 0, 0, 0, 0])
+        {
+            data.extend(u32::to_le_bytes(field));
+        }
+        data.extend_from_slice(image.as_raw());
+        data
+    }
+
+    /// Writes an 8-bit RGB PSD with `base` as the bottom layer and each of
+    /// `layers` above it at its offset, with every channel uncompressed and
+    /// the base alone as the merged image.
+    fn encode_psd(
+        base_name: &str,
+        base: &RgbaImage,
+        layers: &[(String, u32, u32, RgbaImage)],
+    ) -> Vec<u8> {
+        let (width, height) = base.dimensions();
+        let mut data = b"8BPS".to_vec();
+        data.extend(1u16.to_be_bytes());
+        data.extend([0; 6]);
+        data.extend(4u16.to_be_bytes());
+        data.extend(height.to_be_bytes());
+        data.extend(width.to_be_bytes());
+        data.extend(8u16.to_be_bytes());
+        data.extend(3u16.to_be_bytes());
+        data.extend([0; 8]);
+        let layers = [(base_name, 0, 0, base)]
+            .into_iter()
+            .chain(
+                layers
+                    .iter()
+                    .map(|(name, x, y, image)| (name.as_str(), *x, *y, image)),
+            )
+            .collect::<Vec<_>>();
+        let mut info = (layers.len() as u16).to_be_bytes().to_vec();
+        for &(name, x, y, image) in &layers {
+            let (width, height) = image.dimensions();
+            for field in [y, x, y + height, x + width] {
+                info.extend(field.to_be_bytes());
+            }
+            info.extend(4u16.to_be_bytes());
+            for id in [-1i16, 0, 1, 2] {
+                info.extend(id.to_be_bytes());
+                info.extend((2 + width * height).to_be_bytes());
+            }
+            info.extend(b"8BIMnorm");
+            info.extend([0xff, 0, 0, 0]);
+            let name = &name.as_bytes()[..name.len().min(0xff)];
+            let mut extra = vec![0; 8];
+            extra.push(name.len() as u8);
+            extra.extend(name);
+            extra.resize(8 + (name.len() + 1).next_multiple_of(4), 0);
+            info.extend((extra.len() as u32).to_be_bytes());
+            info.extend(extra);
+        }
+        for &(_, _, _, image) in &layers {
+            for channel in [3, 0, 1, 2] {
+                info.extend([0, 0]);
+                info.extend(image.pixels().map(|p| p[channel]));
+            }
+        }
+        if info.len() % 2 == 1 {
+            info.push(0);
+        }
+        data.extend((info.len() as u32 + 8).to_be_bytes());
+        data.extend((info.len() as u32).to_be_bytes());
+        data.extend(info);
+        data.extend([0; 6]);
+        for channel in 0..4 {
+            data.extend(base.pixels().map(|p| p[channel]));
+        }
+        data
+    }
+
+    /// Writes a palette PNG when the image has at most 256 distinct colors,
+    /// with a `tRNS` chunk only if some palette entry is not opaque.
+    fn encode_indexed(image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut palette = HashMap::new();
+        let mut colors = vec![];
+        let mut indices = Vec::with_capacity(image.len() / 4);
+        for pixel in image.pixels() {
+            let index = *palette.entry(pixel.0).or_insert_with(|| {
+                colors.push(pixel.0);
+                colors.len() - 1
+            });
+            if index > 0xff {
+                return Ok(None);
+            }
+            indices.push(index as u8);
+        }
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, image.width(), image.height());
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(
+            colors
+                .iter()
+                .flat_map(|c| [c[0], c[1], c[2]])
+                .collect::<Vec<_>>(),
+        );
+        if colors.iter().any(|c| c[3] != 0xff) {
+            encoder.set_trns(colors.iter().map(|c| c[3]).collect::<Vec<_>>());
+        }
+        encoder.write_header()?.write_image_data(&indices)?;
+        Ok(Some(data))
+    }
+}
+
+/// How a sub's pixels are combined with its base.
+#[derive(Clone, Copy, Default)]
+enum Blend {
+    /// The stored sub is a XOR delta against the base.
+    #[default]
+    Xor,
+    /// The sub is an overlay composited source-over by its alpha, with
+    /// 3-channel subs fully opaque.
+    Over,
+}
+
+impl Blend {
+    fn over(base: &mut Rgba<u8>, sub: &Rgba<u8>) {
+        let (sa, da) = (sub[3] as u32, base[3] as u32);
+        let alpha = sa * 255 + da * (255 - sa);
+        if alpha == 0 {
+            return;
+        }
+        for i in 0..3 {
+            let color = sub[i] as u32 * sa * 255 + base[i] as u32 * da * (255 - sa);
+            base[i] = ((color + alpha / 2) / alpha) as u8;
+        }
+        base[3] = ((alpha + 127) / 255) as u8;
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct DecodeOptions {
+    alpha_threshold: Option<u8>,
+    gamma: Option<f32>,
+    flip_horizontal: bool,
+    rotate: u16,
+    bgra: bool,
+    blend: Blend,
+    max_width: Option<usize>,
+    max_height: Option<usize>,
+    timeout: Option<Duration>,
+    lenient: bool,
+    flatten_subs: bool,
+}
+
+thread_local! {
+    static CLAMPS: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+impl DecodeOptions {
+    const MAX_DIMENSION: usize = 0x4000;
+
+    /// Fails on damaged data, or with `--lenient` records the clamp for the
+    /// current thread and lets the decoder carry on with zero-filled data.
+    fn clamp(&self, message: String) -> anyhow::Result<()> {
+        if !self.lenient {
+            anyhow::bail!(message);
+        }
+        CLAMPS.with_borrow_mut(|clamps| clamps.push(message));
+        Ok(())
+    }
+
+    /// Checks that `data` holds `len` bytes of `what` before it is split,
+    /// zero-filling the shortfall when lenient.
+    fn require(&self, what: &str, data: &mut Vec<u8>, len: usize) -> anyhow::Result<()> {
+        if data.len() < len {
+            self.clamp(format!(
+                "{what} is {:#x} of {len:#x} bytes, {:#x} short",
+                data.len(),
+                len - data.len(),
+            ))?;
+            data.resize(len, 0);
+        }
+        Ok(())
+    }
+
+    fn take_clamps() -> Vec<String> {
+        CLAMPS.take()
+    }
+
+    /// Whether `apply` changes anything.
+    fn transforms(&self) -> bool {
+        self.alpha_threshold.is_some()
+            || self.gamma.is_some_and(|gamma| gamma != 1.0)
+            || self.flip_horizontal
+            || matches!(self.rotate, 90 | 180 | 270)
+            || self.bgra
+    }
+
+    /// Applies, in order: alpha threshold, gamma, horizontal flip, clockwise rotation,
+    /// and finally the `--color-order` swizzle.
+    ///
+    /// This runs on the final composited image, so sub placement is done in the
+    /// source orientation and needs no adjustment.
+    fn apply<'a>(
+        &self,
+        image: &'a ImageBuffer<Rgba<u8>, Vec<u8>>,
+    ) -> Cow<'a, ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let mut image = Cow::Borrowed(image);
+        if let Some(threshold) = self.alpha_threshold {
+            for pixel in image.to_mut().pixels_mut() {
+                if pixel[3] < threshold {
+                    pixel[3] = 0;
+                } else if pixel[3] > 0xff - threshold {
+                    pixel[3] = 0xff;
+                }
+            }
+        }
+        if let Some(gamma) = self.gamma.filter(|&gamma| gamma != 1.0) {
+            let table: [u8; 256] = std::array::from_fn(|i| {
+                ((i as f32 / 255.0).powf(1.0 / gamma) * 255.0).round() as u8
+            });
+            for pixel in image.to_mut().pixels_mut() {
+                for channel in &mut pixel.0[..3] {
+                    *channel = table[*channel as usize];
+                }
+            }
+        }
+        if self.flip_horizontal {
+            image = Cow::Owned(imageops::flip_horizontal(&*image));
+        }
+        match self.rotate {
+            90 => image = Cow::Owned(imageops::rotate90(&*image)),
+            180 => image = Cow::Owned(imageops::rotate180(&*image)),
+            270 => image = Cow::Owned(imageops::rotate270(&*image)),
+            _ => {}
+        }
+        if self.bgra {
+            for pixel in image.to_mut().pixels_mut() {
+                pixel.0.swap(0, 2);
+            }
+        }
+        image
+    }
+}
+
+#[derive(Default)]
+pub struct Options {
+    rgb_when_opaque: bool,
+    palette_output: bool,
+    mains_only: bool,
+    subs_only: bool,
+    raw_only: bool,
+    no_subimages: bool,
+    dedup_hardlink: bool,
+    verify_only: bool,
+    list_unsupported: bool,
+    fallback_raw: bool,
+    /// How many of the slowest entries `--profile` reports at the end.
+    profile: Option<usize>,
+    fail_on_unsupported: bool,
+    newer_than: Option<SystemTime>,
+    auto_decompress: bool,
+    name_encoding: Option<&'static Encoding>,
+    decode: DecodeOptions,
+    only_new: bool,
+    resume: bool,
+    normalize_newlines: bool,
+    sidecar: bool,
+    checksums: bool,
+    verify_output: bool,
+    quiet: bool,
+    verbose: u8,
+    append_extension: bool,
+    zip: Option<PathBuf>,
+    tar: Option<PathBuf>,
+    contact_sheet: Option<PathBuf>,
+    limit: Option<usize>,
+    limit_per_pac: bool,
+    psd: bool,
+    export_base_with_subs: bool,
+    no_composite: bool,
+    trim: bool,
+    dump_planes: bool,
+    retries: u32,
+    preserve_case: bool,
+    index_names: bool,
+    merge: bool,
+    cache_dir: Option<PathBuf>,
+    region: Option<(usize, usize, usize, usize)>,
+    /// Payload alignment for `repair`, from `--align`; `None` packs entries
+    /// back to back like the original archives.
+    align: Option<u64>,
+    format: OutputFormat,
+    sort: EntryOrder,
+    /// Decode threads, taken from `--threads`, then `CRATRI_UNPAC_THREADS`,
+    /// then 0 to let rayon use the CPU count.
+    threads: usize,
+    io_workers: usize,
+    /// Bytes of decoded mains kept across archives, from `--image-cache`; 0
+    /// disables the cache.
+    image_cache: usize,
+    /// PAC handles open at once, from `--max-open-files`, 0 for the default.
+    /// Each `--threads` worker holds at most one extra handle while reading a
+    /// split part, so a limit below the thread count only makes workers wait.
+    max_open_files: usize,
+}
+
+impl Options {
+    pub fn parse() -> anyhow::Result<(Command, Self)> {
+        let mut command = Command::default();
+        let mut options = Self::default();
+        if let Ok(threads) = env::var("CRATRI_UNPAC_THREADS") {
+            options.threads = threads
+                .parse()
+                .with_context(|| format!("invalid CRATRI_UNPAC_THREADS: {threads}"))?;
+        }
+        let mut args = env::args().skip(1).peekable();
+        if args.next_if_eq("cat").is_some() {
+            let (Some(pac), Some(entry)) = (args.next(), args.next()) else {
+                anyhow::bail!("usage: cat <pac> <entry>");
+            };
+            command = Command::Cat { pac, entry };
+        } else if args.next_if_eq("probe").is_some() {
+            let Some(pac) = args.next() else {
+                anyhow::bail!("usage: probe <pac>");
+            };
+            command = Command::Probe { pac };
+        } else if args.next_if_eq("diff").is_some() {
+            let (Some(a), Some(b)) = (args.next(), args.next()) else {
+                anyhow::bail!("usage: diff <dir_a> <dir_b>");
+            };
+            command = Command::Diff { a, b };
+        } else if args.next_if_eq("plan").is_some() {
+            let (Some(pac), Some(dir)) = (args.next(), args.next()) else {
+                anyhow::bail!("usage: plan <pac> <dir>");
+            };
+            command = Command::Plan { pac, dir };
+        } else if args.next_if_eq("repair").is_some() {
+            let (Some(pac), Some(dir), Some(out)) = (args.next(), args.next(), args.next()) else {
+                anyhow::bail!("usage: repair <pac> <dir> <out>");
+            };
+            command = Command::Repair { pac, dir, out };
+        } else if args.next_if_eq("deps").is_some() {
+            let Some(pac) = args.next() else {
+                anyhow::bail!("usage: deps <pac>");
+            };
+            command = Command::Deps { pac };
+        } else if args.next_if_eq("replace").is_some() {
+            let (Some(pac), Some(entry), Some(file), Some(out)) =
+                (args.next(), args.next(), args.next(), args.next())
+            else {
+                anyhow::bail!("usage: replace <pac> <entry> <file> <out>");
+            };
+            command = Command::Replace {
+                pac,
+                entry,
+                file,
+                out,
+            };
+        }
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--rgb-when-opaque" => options.rgb_when_opaque = true,
+                "--palette-output" => options.palette_output = true,
+                "--mains-only" => options.mains_only = true,
+                "--subs-only" => options.subs_only = true,
+                "--raw-only" => options.raw_only = true,
+                "--no-subimages" => options.no_subimages = true,
+                "--dedup-hardlink" => options.dedup_hardlink = true,
+                "--verify-only" => options.verify_only = true,
+                "--list-unsupported" => options.list_unsupported = true,
+                "--fallback-raw" => options.fallback_raw = true,
+                "--fail-on-unsupported" => options.fail_on_unsupported = true,
+                "--profile" => {
+                    let top = args.next_if(|arg| arg.parse::<usize>().is_ok());
+                    options.profile = Some(top.map_or(Ok(10), |top| top.parse())?);
+                }
+                "--lenient" => options.decode.lenient = true,
+                "--flatten-subs" => options.decode.flatten_subs = true,
+                "--auto-decompress" => options.auto_decompress = true,
+                "--only-new" => options.only_new = true,
+                "--resume" => options.resume = true,
+                "--normalize-newlines" => options.normalize_newlines = true,
+                "--sidecar" => options.sidecar = true,
+                "--checksums" => options.checksums = true,
+                "--verify-output" => options.verify_output = true,
+                "--append-extension" => options.append_extension = true,
+                "--name-encoding" => {
+                    options.name_encoding = Some(match Self::value(&mut args, &arg)?.as_str() {
+                        "shift-jis" => encoding_rs::SHIFT_JIS,
+                        "gbk" => encoding_rs::GBK,
+                        "utf8" => encoding_rs::UTF_8,
+                        "latin1" => encoding_rs::WINDOWS_1252,
+                        encoding => anyhow::bail!("unsupported name encoding: {encoding}"),
+                    });
+                }
+                "--zip" => options.zip = Some(Self::value(&mut args, &arg)?.into()),
+                "--tar" => options.tar = Some(Self::value(&mut args, &arg)?.into()),
+                "--contact-sheet" => {
+                    options.contact_sheet = Some(Self::value(&mut args, &arg)?.into());
+                }
+                "--sort" => {
+                    options.sort = match Self::value(&mut args, &arg)?.as_str() {
+                        "name" => EntryOrder::Name,
+                        "offset" => EntryOrder::Offset,
+                        "size" => EntryOrder::Size,
+                        order => anyhow::bail!("unsupported sort order: {order}"),
+                    };
+                }
+                "--format" => {
+                    options.format = match Self::value(&mut args, &arg)?.as_str() {
+                        "png" => OutputFormat::Png,
+                        "qoi" => OutputFormat::Qoi,
+                        "dds" => OutputFormat::Dds,
+                        format => anyhow::bail!("unsupported output format: {format}"),
+                    };
+                }
+                "-q" | "--quiet" => options.quiet = true,
+                "-v" | "--verbose" => options.verbose += 1,
+                "-vv" => options.verbose += 2,
+                "--threads" => options.threads = Self::value(&mut args, &arg)?.parse()?,
+                "--io-workers" => options.io_workers = Self::value(&mut args, &arg)?.parse()?,
+                "--image-cache" => {
+                    options.image_cache = Self::value(&mut args, &arg)?.parse()?;
+                }
+                "--max-open-files" => {
+                    options.max_open_files = Self::value(&mut args, &arg)?.parse()?;
+                }
+                "--limit" => options.limit = Some(Self::value(&mut args, &arg)?.parse()?),
+                "--limit-per-pac" => options.limit_per_pac = true,
+                "--psd" => options.psd = true,
+                "--export-base-with-subs" => options.export_base_with_subs = true,
+                "--no-composite" => options.no_composite = true,
+                "--trim" => options.trim = true,
+                "--dump-planes" => options.dump_planes = true,
+                "--retries" => options.retries = Self::value(&mut args, &arg)?.parse()?,
+                "--preserve-case" => options.preserve_case = true,
+                "--index-names" => options.index_names = true,
+                "--merge" => options.merge = true,
+                "--cache-dir" => options.cache_dir = Some(Self::value(&mut args, &arg)?.into()),
+                "--align" => {
+                    options.align = match Self::value(&mut args, &arg)?.parse()? {
+                        0 => anyhow::bail!("--align must be at least 1"),
+                        align => Some(align),
+                    };
+                }
+                "--region" => {
+                    let region = Self::value(&mut args, &arg)?;
+                    let fields = region
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let &[x, y, width, height] = fields.as_slice() else {
+                        anyhow::bail!("--region expects x,y,width,height, got {region}");
+                    };
+                    options.region = Some((x, y, width, height));
+                }
+                "--gamma" => {
+                    let gamma: f32 = Self::value(&mut args, &arg)?.parse()?;
+                    if gamma.is_nan() || gamma <= 0.0 {
+                        anyhow::bail!("--gamma must be positive");
+                    }
+                    options.decode.gamma = Some(gamma);
+                }
+                "--flip-horizontal" => options.decode.flip_horizontal = true,
+                "--rotate" => {
+                    options.decode.rotate = match Self::value(&mut args, &arg)?.parse()? {
+                        rotate @ (0 | 90 | 180 | 270) => rotate,
+                        rotate => anyhow::bail!("unsupported rotation: {rotate}"),
+                    };
+                }
+                "--blend" => {
+                    options.decode.blend = match Self::value(&mut args, &arg)?.as_str() {
+                        "xor" => Blend::Xor,
+                        "over" => Blend::Over,
+                        blend => anyhow::bail!("unsupported blend mode: {blend}"),
+                    };
+                }
+                "--color-order" => {
+                    options.decode.bgra = match Self::value(&mut args, &arg)?.as_str() {
+                        "rgba" => false,
+                        "bgra" => true,
+                        order => anyhow::bail!("unsupported color order: {order}"),
+                    };
+                }
+                "--max-width" => {
+                    options.decode.max_width = Some(Self::value(&mut args, &arg)?.parse()?);
+                }
+                "--max-height" => {
+                    options.decode.max_height = Some(Self::value(&mut args, &arg)?.parse()?);
+                }
+                "--alpha-threshold" => {
+                    options.decode.alpha_threshold = Some(Self::value(&mut args, &arg)?.parse()?);
+                }
+                "--entry-timeout" => {
+                    let secs = Self::value(&mut args, &arg)?.parse()?;
+                    match Duration::try_from_secs_f64(secs) {
+                        Ok(timeout) => options.decode.timeout = Some(timeout),
+                        Err(e) => anyhow::bail!("invalid --entry-timeout {secs}: {e}"),
+                    }
+                }
+                "--newer-than" => {
+                    let secs = Self::value(&mut args, &arg)?.parse()?;
+                    options.newer_than = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                _ => anyhow::bail!("unknown option: {arg}"),
+            }
+        }
+        if options.zip.is_some() && options.tar.is_some() {
+            anyhow::bail!("--zip and --tar are mutually exclusive");
+        }
+        if options.mains_only && options.subs_only {
+            anyhow::bail!("--mains-only and --subs-only are mutually exclusive");
+        }
+        if options.subs_only && options.no_subimages {
+            anyhow::bail!("--subs-only and --no-subimages are mutually exclusive");
+        }
+        Ok((command, options))
+    }
+
+    fn value(args: &mut impl Iterator<Item = String>, arg: &str) -> anyhow::Result<String> {
+        args.next()
+            .ok_or_else(|| anyhow::anyhow!("missing value for {arg}"))
+    }
+
+    fn limited(&self, extracted: usize) -> bool {
+        self.limit.is_some_and(|limit| extracted >= limit)
+    }
+
+    /// Whether PNG output can be encoded row by row from the decoded pixels.
+    fn streams(&self) -> bool {
+        matches!(self.format, OutputFormat::Png)
+            && !self.palette_output
+            && !self.rgb_when_opaque
+            && !self.decode.transforms()
+    }
+
+    fn mains(&self) -> bool {
+        self.mains_only || !self.subs_only && !self.raw_only
+    }
+
+    fn subs(&self) -> bool {
+        !self.no_subimages && (self.subs_only || !self.mains_only && !self.raw_only)
+    }
+
+    fn raw(&self) -> bool {
+        self.raw_only || !self.mains_only && !self.subs_only
+    }
+
+    /// Whether unsupported entries are written raw instead of failing the run.
+    fn fallback(&self) -> bool {
+        self.fallback_raw && !self.fail_on_unsupported
+    }
+
+    /// Whether `e` aborts the run even under `--verify`.
+    fn fatal(&self, e: &anyhow::Error) -> bool {
+        self.fail_on_unsupported && e.downcast_ref::<Unsupported>().is_some()
+    }
+}
+
+/// Header of a `GE \0` main image, read right after the magic.
+///
+/// | offset | size | field       |
+/// |--------|------|-------------|
+/// | 0x00   | 4    | magic       |
+/// | 0x04   | 8    | unknown_04  |
+/// | 0x0c   | 4    | width       |
+/// | 0x10   | 4    | height      |
+/// | 0x14   | 8    | unknown_14  |
+/// | 0x1c   | 2    | filter_type |
+/// | 0x1e   | 2    | unknown_1e  |
+/// | 0x20   | 4    | size_orig   |
+/// | 0x24   | 4    | size_comp   |
+/// | 0x28   |      | payload     |
+///
+/// The unknown fields are kept as read and shown by `-vv` and sidecars so
+/// variants that decode wrong can be correlated with them.
+#[derive(Clone)]
+struct MainHeader {
+    unknown_04: [u8; 8],
+    width: usize,
+    height: usize,
+    unknown_14: [u8; 8],
+    filter_type: u16,
+    unknown_1e: u16,
+    size_orig: usize,
+    size_comp: usize,
+}
+
+impl MainHeader {
+    const SIZE: usize = 0x24;
+
+    /// The channel count of a filter_type 2 image: the header has no channel
+    /// field, but `size_orig` makes room for an alpha plane after the luma
+    /// plane exactly when there is one. filter_type 3 reads its own from the
+    /// delta header instead. Dimensions too large for the plane sizes to fit
+    /// in a usize have no room for one either.
+    fn channels(&self) -> usize {
+        let planes = self
+            .width
+            .checked_mul(self.height)
+            .and_then(|size| size.checked_mul(5));
+        match planes.is_some_and(|planes| self.size_orig >= planes / 2) {
+            true => 4,
+            false => 3,
+        }
+    }
+
+    fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut header = [0; Self::SIZE];
+        reader.read_exact(&mut header)?;
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            unknown_04: header[0x00..0x08].try_into().unwrap(),
+            width: u32_at(0x08) as usize,
+            height: u32_at(0x0c) as usize,
+            unknown_14: header[0x10..0x18].try_into().unwrap(),
+            filter_type: u16_at(0x18),
+            unknown_1e: u16_at(0x1a),
+            size_orig: u32_at(0x1c) as usize,
+            size_comp: u32_at(0x20) as usize,
+        })
+    }
+}
+
+/// Header of a `PGD3` sub image, read right after the magic.
+///
+/// | offset | size | field     |
+/// |--------|------|-----------|
+/// | 0x00   | 4    | magic     |
+/// | 0x04   | 2    | x         |
+/// | 0x06   | 2    | y         |
+/// | 0x08   | 2    | width     |
+/// | 0x0a   | 2    | height    |
+/// | 0x0c   | 2    | bpp       |
+/// | 0x0e   | 32   | base name |
+/// | 0x2e   | 2    | flags     |
+/// | 0x30   | 4    | size_orig |
+/// | 0x34   | 4    | size_comp |
+/// | 0x38   |      | payload   |
+#[derive(Clone)]
+struct SubHeader {
+    x: u32,
+    y: u32,
+    width: usize,
+    height: usize,
+    channels: usize,
+    base: String,
+    /// Passed through to `--probe` and sidecars. The meaning of its bits is not
+    /// known yet, so compositing ignores it and still XORs with a zero alpha
+    /// default.
+    flags: u16,
+    size_orig: usize,
+    size_comp: usize,
+}
+
+impl SubHeader {
+    const SIZE: usize = 0x34;
+    /// No header field selecting a sub's filter has been identified, so subs
+    /// always go through the delta filter.
+    const FILTER_TYPE: u16 = 3;
+
+    fn read(reader: &mut impl Read) -> anyhow::Result<Self> {
+        let mut header = [0; Self::SIZE];
+        reader.read_exact(&mut header)?;
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            x: u16_at(0x00) as u32,
+            y: u16_at(0x02) as u32,
+            width: u16_at(0x04) as usize,
+            height: u16_at(0x06) as usize,
+            channels: GeImage::channels(u16_at(0x08))?,
+            base: CStr::from_bytes_until_nul(&header[0x0a..0x2a])?
+                .to_string_lossy()
+                .to_lowercase(),
+            flags: u16_at(0x2a),
+            size_orig: u32_at(0x2c) as usize,
+            size_comp: u32_at(0x30) as usize,
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[derive(Clone)]
+enum Header {
+    Main(MainHeader),
+    Sub(SubHeader),
+}
+
+/// One entry as the `--checksums` manifest records it. Images also carry
+/// their header fields, and `--sidecar` writes the same record next to each
+/// image.
+#[derive(Clone, Default)]
+struct Entry {
+    header: Option<Header>,
+    /// `None` for a filter_type 3 main whose payload does not start with a
+    /// readable delta header.
+    channels: Option<usize>,
+    /// Over the compressed payload for images, the whole entry otherwise.
+    crc32: Option<u32>,
+    raw_name: Option<Vec<u8>>,
+    trim: Option<(u32, u32)>,
+}
+
+impl Entry {
+    /// Probes the header in `head`, the start of the entry.
+    fn probe(head: &[u8]) -> Self {
+        let header = GeImage::probe(&mut &head[..]).ok();
+        let channels = match &header {
+            Some(Header::Main(h)) if h.filter_type == 2 => Some(h.channels()),
+            Some(Header::Main(h)) if h.filter_type == 3 => {
+                GeImage::delta_channels(h, head.get(4 + MainHeader::SIZE..).unwrap_or_default())
+            }
+            Some(Header::Sub(h)) => Some(h.channels),
+            _ => None,
+        };
+        Self {
+            header,
+            channels,
+            ..Default::default()
+        }
+    }
+
+    /// The CRC32 of `data`, the whole entry, as `crc32` records it.
+    fn checksum(&self, data: &[u8]) -> u32 {
+        let payload = match &self.header {
+            Some(Header::Main(h)) => data.get(4 + MainHeader::SIZE..).map(|p| (p, h.size_comp)),
+            Some(Header::Sub(h)) => data.get(4 + SubHeader::SIZE..).map(|p| (p, h.size_comp)),
+            None => None,
+        };
+        crc32fast::hash(payload.map_or(data, |(p, len)| &p[..p.len().min(len)]))
+    }
+
+    fn sub(&self) -> Option<&SubHeader> {
+        match &self.header {
+            Some(Header::Sub(header)) => Some(header),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut entry = match &self.header {
+            Some(Header::Main(h)) => serde_json::json!({
+                "kind": "main",
+                "width": h.width,
+                "height": h.height,
+                "filter_type": h.filter_type,
+                "channels": self.channels,
+                "unknown_04": hex(&h.unknown_04),
+                "unknown_14": hex(&h.unknown_14),
+                "unknown_1e": h.unknown_1e,
+            }),
+            Some(Header::Sub(h)) => serde_json::json!({
+                "kind": "sub",
+                "width": h.width,
+                "height": h.height,
+                "channels": self.channels,
+                "base": h.base,
+                "flags": h.flags,
+                "x": h.x,
+                "y": h.y,
+            }),
+            None => serde_json::json!({}),
+        };
+        let fields = entry.as_object_mut().unwrap();
+        if let Some(crc32) = self.crc32 {
+            fields.insert("crc32".into(), format!("{crc32:08x}").into());
+        }
+        if let Some(raw_name) = &self.raw_name {
+            fields.insert("raw_name".into(), hex(raw_name).into());
+        }
+        if let Some((x, y)) = self.trim {
+            fields.insert("trim".into(), serde_json::json!([x, y]));
+        }
+        entry
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Header::Main(h) => write!(
+                f,
+                "main {}x{} filter_type={} size_orig={} size_comp={} \
+                 unknown_04={} unknown_14={} unknown_1e={:#06x}",
+                h.width,
+                h.height,
+                h.filter_type,
+                h.size_orig,
+                h.size_comp,
+                hex(&h.unknown_04),
+                hex(&h.unknown_14),
+                h.unknown_1e,
+            ),
+            Header::Sub(h) => write!(
+                f,
+                "sub {}x{}+{}+{} channels={} base={} flags={:#06x} size_orig={} size_comp={}",
+                h.width, h.height, h.x, h.y, h.channels, h.base, h.flags, h.size_orig, h.size_comp,
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Unsupported(String);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Unsupported {}
+
+#[derive(Debug)]
+struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("decode timed out")
+    }
+}
+
+impl Error for TimedOut {}
+
+/// Scratch buffers reused by one worker across successive entries.
+#[derive(Default)]
+pub struct DecodeContext {
+    compressed: Vec<u8>,
+    data: Vec<u8>,
+    pixels: Vec<u8>,
+    /// Where the next payload's decompressed bytes are cached, if anywhere.
+    cache: Option<PathBuf>,
+}
+
+impl DecodeContext {
+    /// The cache file for an entry, keyed by archive, name, offset and length
+    /// so that a moved or resized entry misses.
+    fn cache_path(dir: &Path, pac: &PacData, name: &str) -> Option<PathBuf> {
+        let &(offset, len) = pac.metadata.get(&name.to_lowercase())?;
+        Some(dir.join(format!(
+            "{:08x}-{}-{offset:x}-{len:x}",
+            crc32fast::hash(pac.name.as_bytes()),
+            name.replace(['/', '\\'], "_"),
+        )))
+    }
+
+    fn read_payload(
+        &mut self,
+        file: &mut impl Read,
+        size_comp: usize,
+        size_orig: usize,
+        options: &DecodeOptions,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<()> {
+        let cache = self.cache.take();
+        if let Some(cached) = cache.as_ref().and_then(|path| fs::read(path).ok())
+            && cached.len() == size_orig
+        {
+            io::copy(&mut file.take(size_comp as u64), &mut io::sink())?;
+            self.data = cached;
+            return Ok(());
+        }
+        self.compressed.clear();
+        file.take(size_comp as u64)
+            .read_to_end(&mut self.compressed)?;
+        if self.compressed.len() < size_comp {
+            options.clamp(format!(
+                "size_comp {size_comp:#x} overruns the entry by {:#x} bytes",
+                size_comp - self.compressed.len(),
+            ))?;
+        }
+        GeImage::decompress_into(
+            &self.compressed,
+            size_orig,
+            &mut self.data,
+            options,
+            deadline,
+        )?;
+        if let Some(path) = cache
+            && self.data.len() == size_orig
+        {
+            fs::write(path, &self.data)?;
+        }
+        Ok(())
+    }
+}
+
+type Plane = (&'static str, usize, usize, Vec<u8>);
+
+/// Decodes an entry's bytes after its magic. `images` holds the archive's
+/// decoded mains and is only populated for subs.
+pub type DecoderFn = fn(
+    &mut &[u8],
+    &HashMap<Arc<String>, RgbaImage>,
+    &DecodeOptions,
+    &mut DecodeContext,
+) -> anyhow::Result<RgbaImage>;
+
+pub struct GeImage;
+
+impl GeImage {
+    const MAIN_MAGIC: [u8; 4] = [0x47, 0x45, 0x20, 0x00];
+    const SUB_MAGIC: [u8; 4] = [0x50, 0x47, 0x44, 0x33];
+    /// The magic plus the larger of the two headers.
+    const HEADER_LEN: usize = 4 + SubHeader::SIZE;
+    const FILTERS: [u16; 2] = [2, 3];
+    const DELTA_MODES: [u8; 4] = [0, 1, 2, 4];
+
+    /// The built-in decoders, keyed by magic.
+    pub fn decoders() -> HashMap<[u8; 4], DecoderFn> {
+        HashMap::from([
+            (
+                GeImage::MAIN_MAGIC,
+                (|file, _, options, context| GeImage::decode_main_with(file, options, context))
+                    as DecoderFn,
+            ),
+            (GeImage::SUB_MAGIC, |file, images, options, context| {
+                GeImage::decode_sub_with(file, images, options, context)
+            }),
+        ])
+    }
+
+    /// The main image filter_type values `unfilter` handles.
+    pub fn supported_filters() -> &'static [u16] {
+        &GeImage::FILTERS
+    }
+
+    /// The per-row delta modes `apply_delta_filter` handles.
+    pub fn supported_delta_modes() -> &'static [u8] {
+        &GeImage::DELTA_MODES
+    }
+
+    /// 48 and 64 bpp would be 16 bits per channel. No such sample is known,
+    /// so they are reported as unsupported rather than decoded as 8-bit.
+    fn channels(bpp: u16) -> anyhow::Result<usize> {
+        match bpp {
+            8 | 16 | 24 | 32 => Ok(bpp as usize >> 3),
+            48 | 64 => Err(Unsupported(format!(
+                "{bpp} bits per pixel (16 bits per channel) is not supported"
+            ))
+            .into()),
+            bpp => anyhow::bail!("unsupported bits per pixel: {bpp}"),
+        }
+    }
+
+    /// The channel count in a filter_type 3 main's delta header, decompressed
+    /// from `payload`, the start of its compressed payload.
+    fn delta_channels(header: &MainHeader, payload: &[u8]) -> Option<usize> {
+        let payload = &payload[..payload.len().min(header.size_comp)];
+        let data = GeImage::decompress(payload, 8, &DecodeOptions::default(), None).ok()?;
+        GeImage::channels(u16::from_le_bytes([data[2], data[3]])).ok()
+    }
+
+    fn check_deadline(deadline: Option<Instant>) -> anyhow::Result<()> {
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            return Err(TimedOut.into());
+        }
+        Ok(())
+    }
+
+    fn decompress(
+        input: &[u8],
+        size_orig: usize,
+        options: &DecodeOptions,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut output = vec![];
+        GeImage::decompress_into(input, size_orig, &mut output, options, deadline)?;
+        Ok(output)
+    }
+
+    fn decompress_into(
+        input: &[u8],
+        size_orig: usize,
+        output: &mut Vec<u8>,
+        options: &DecodeOptions,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<()> {
+        output.clear();
+        output.resize(size_orig, 0);
+        let mut output_pos = 0;
+        let mut input_pos = 0;
+        let mut control = 0;
+        let mut steps = 0u32;
+        while output_pos < output.len() {
+            steps = steps.wrapping_add(1);
+            if steps & 0xffff == 0 {
+                GeImage::check_deadline(deadline)?;
+            }
+            control >>= 1;
+            if 0 == control & 0x0100 {
+                let Some(&byte) = input.get(input_pos) else {
+                    break;
+                };
+                control = byte as u16 | 0xff00;
+                input_pos += 1;
+            }
+            if 0 == control & 1 {
+                let Some(&repetitions) = input.get(input_pos) else {
+                    break;
+                };
+                input_pos += 1;
+                let len = (repetitions as usize).min(output.len() - output_pos);
+                let literal = input.get(input_pos..).unwrap_or_default();
+                let literal = &literal[..len.min(literal.len())];
+                output[output_pos..output_pos + literal.len()].copy_from_slice(literal);
+                output_pos += literal.len();
+                input_pos += literal.len();
+                if literal.len() < len {
+                    break;
+                }
+            } else {
+                let Some(&[lo, hi]) = input.get(input_pos..input_pos + 2) else {
+                    break;
+                };
+                let mut tmp = u16::from_le_bytes([lo, hi]) as u32;
+                input_pos += 2;
+                let (mut repetitions, look_behind) = if 0 == tmp & 8 {
+                    let Some(&byte) = input.get(input_pos) else {
+                        break;
+                    };
+                    tmp = tmp << 8 | byte as u32;
+                    input_pos += 1;
+                    (((tmp & 0x0ffc) >> 2) + 1 << 2 | tmp & 3, tmp >> 12)
+                } else {
+                    ((tmp & 7) + 4, tmp >> 4)
+                };
+                let Some(mut pos) = output_pos.checked_sub(look_behind as usize) else {
+                    options.clamp(format!(
+                        "back-reference {look_behind:#x} before start at {output_pos:#x}"
+                    ))?;
+                    output_pos = (output_pos + repetitions as usize).min(output.len());
+                    continue;
+                };
+                while output_pos < output.len() && repetitions > 0 {
+                    output[output_pos] = output[pos];
+                    output_pos += 1;
+                    pos += 1;
+                    repetitions -= 1;
+                }
+            }
+        }
+        if output_pos < output.len() {
+            options.clamp(format!(
+                "compressed data ends at {input_pos:#x}, zero-filled {:#x} bytes",
+                output.len() - output_pos,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Converts planar YUV 4:2:0 (U at 0, V at size / 4, Y at size / 2) into BGR,
+    /// or into BGRA when `channels` is 4 and an alpha plane follows the luma plane.
+    ///
+    /// Chroma is stored once per 2x2 block and the plane offsets leave no room for
+    /// partial blocks, so the format only admits even dimensions.
+    fn apply_filter(
+        data: &[u8],
+        output: &mut Vec<u8>,
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) {
+        let stride = width * channels;
+        let size = width * height;
+        let _data = unsafe {
+            (ptr::slice_from_raw_parts(data.as_ptr(), data.len()) as *const [i8]).as_ref_unchecked()
+        };
+        let mut plane1 = 0;
+        let mut plane2 = size >> 2;
+        let mut plane3 = size >> 1;
+        output.clear();
+        output.resize(height * stride, 0);
+        let mut output_pos = 0;
+        for _ in 0..height >> 1 {
+            for _ in 0..width >> 1 {
+                let b = 226 * _data[plane1] as i32;
+                let g = -43 * _data[plane1] as i32 - 89 * _data[plane2] as i32;
+                let r = 179 * _data[plane2] as i32;
+                for i in [0, 1, width, width + 1] {
+                    let base = (data[plane3 + i] as i32) << 7;
+                    let pos = output_pos + channels * i;
+                    output[pos] = (base + b >> 7).clamp(0, 255) as u8;
+                    output[pos + 1] = (base + g >> 7).clamp(0, 255) as u8;
+                    output[pos + 2] = (base + r >> 7).clamp(0, 255) as u8;
+                    if channels == 4 {
+                        output[pos + 3] = data[plane3 + size + i];
+                    }
+                }
+                plane1 += 1;
+                plane2 += 1;
+                plane3 += 2;
+                output_pos += channels * 2;
+            }
+            plane3 += width;
+            output_pos += stride;
+        }
+    }
+
+    /// Splits a filter_type 2 payload into its raw chroma, luma and any alpha
+    /// planes, or nothing for other filters.
+    fn planes(file: &mut impl Read, options: &DecodeOptions) -> anyhow::Result<Vec<Plane>> {
+        let header = MainHeader::read(file)?;
+        if header.filter_type != 2 {
+            return Ok(vec![]);
+        }
+        let (width, height) = (header.width, header.height);
+        let mut context = DecodeContext::default();
+        context.read_payload(file, header.size_comp, header.size_orig, options, None)?;
+        let size = width * height;
+        Ok([
+            ("plane1", 0, width >> 1, height >> 1),
+            ("plane2", size >> 2, width >> 1, height >> 1),
+            ("plane3", size >> 1, width, height),
+            ("plane4", size * 3 / 2, width, height),
+        ]
+        .into_iter()
+        .filter(|&(_, _, width, height)| width * height > 0)
+        .filter_map(|(name, start, width, height)| {
+            let plane = context.data.get(start..start + width * height)?;
+            Some((name, width, height, plane.to_vec()))
+        })
+        .collect())
+    }
+
+    fn apply_delta_filter(
+        data: &mut [u8],
+        deltas: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<()> {
+        let stride = width * channels;
+        for y in 0..height {
+            GeImage::check_deadline(deadline)?;
+            let (prev, next) = data.split_at_mut(y * stride);
+            let prev = &prev[prev.len().saturating_sub(stride)..];
+            let next = &mut next[..stride];
+            match deltas[y] {
+                0 => {}
+                1 => {
+                    for x in channels..stride {
+                        next[x] = next[x - channels].wrapping_sub(next[x]);
+                    }
+                }
+                2 if y > 0 => {
+                    for x in 0..stride {
+                        next[x] = prev[x].wrapping_sub(next[x]);
+                    }
+                }
+                4 if y > 0 => {
+                    for x in channels..stride {
+                        let mean = (prev[x] as u16 + next[x - channels] as u16) >> 1;
+                        next[x] = (mean as u8).wrapping_sub(next[x]);
+                    }
+                }
+                mode @ (2 | 4) => anyhow::bail!("delta mode {mode} at row {y} has no previous row"),
+                mode => {
+                    return Err(
+                        Unsupported(format!("unsupported delta mode {mode} at row {y}")).into(),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_dimensions(
+        width: usize,
+        height: usize,
+        options: &DecodeOptions,
+    ) -> anyhow::Result<()> {
+        if width == 0 || height == 0 {
+            anyhow::bail!("invalid image dimensions: {width}x{height}");
+        }
+        let max_width = options.max_width.unwrap_or(DecodeOptions::MAX_DIMENSION);
+        let max_height = options.max_height.unwrap_or(DecodeOptions::MAX_DIMENSION);
+        if width > max_width || height > max_height {
+            anyhow::bail!("image dimensions {width}x{height} exceed {max_width}x{max_height}");
+        }
+        Ok(())
+    }
+
+    fn unsupported(reader: &mut impl Read) -> anyhow::Result<Option<String>> {
+        let (deltas, height, size_orig, size_comp) = match GeImage::probe(reader)? {
+            Header::Main(h) if !GeImage::supported_filters().contains(&h.filter_type) => {
+                return Ok(Some(format!("filter_type {}", h.filter_type)));
+            }
+            Header::Main(h) if h.filter_type == 2 => return Ok(None),
+            Header::Main(h) => (8, h.height, h.size_orig, h.size_comp),
+            Header::Sub(h) => (0, h.height, h.size_orig, h.size_comp),
+        };
+        let mut data = vec![0; size_comp];
+        reader.read_exact(&mut data)?;
+        let data = GeImage::decompress(&data, size_orig, &DecodeOptions::default(), None)?;
+        let modes = data
+            .get(deltas..deltas + height)
+            .ok_or_else(|| anyhow::anyhow!("delta image data is truncated"))?
+            .iter()
+            .filter(|mode| !GeImage::supported_delta_modes().contains(mode))
+            .collect::<BTreeSet<_>>();
+        Ok((!modes.is_empty()).then(|| format!("delta modes {modes:?}")))
+    }
+
+    fn probe(reader: &mut impl Read) -> anyhow::Result<Header> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        Ok(match magic {
+            GeImage::MAIN_MAGIC => Header::Main(MainHeader::read(reader)?),
+            GeImage::SUB_MAGIC => Header::Sub(SubHeader::read(reader)?),
+            _ => anyhow::bail!("unknown magic: {magic:02x?}"),
+        })
+    }
+
+    /// Reads a main or sub entry from its magic on and returns the payload
+    /// decompressed but still filtered, as `decode_main` would see it before
+    /// undoing the filter.
+    pub fn decompress_entry(reader: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        let (size_comp, size_orig) = match magic {
+            GeImage::MAIN_MAGIC => {
+                let header = MainHeader::read(reader)?;
+                (header.size_comp, header.size_orig)
+            }
+            GeImage::SUB_MAGIC => {
+                let header = SubHeader::read(reader)?;
+                (header.size_comp, header.size_orig)
+            }
+            magic => return Err(Unsupported(format!("unknown magic: {magic:02x?}")).into()),
+        };
+        let mut context = DecodeContext::default();
+        context.read_payload(
+            reader,
+            size_comp,
+            size_orig,
+            &DecodeOptions::default(),
+            None,
+        )?;
+        Ok(context.data)
+    }
+
+    /// Decodes a main image from a reader positioned right after the magic.
+    #[cfg(test)]
+    fn decode_main(
+        file: &mut impl Read,
+        options: &DecodeOptions,
+    ) -> anyhow::Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        GeImage::decode_main_with(file, options, &mut DecodeContext::default())
+    }
+
+    fn decode_main_with(
+        file: &mut impl Read,
+        options: &DecodeOptions,
+        context: &mut DecodeContext,
+    ) -> anyhow::Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let header = MainHeader::read(file)?;
+        GeImage::decode_main_payload(header, file, options, context)
+    }
+
+    /// Decodes the payload following an already read main header.
+    fn decode_main_payload(
+        header: MainHeader,
+        file: &mut impl Read,
+        options: &DecodeOptions,
+        context: &mut DecodeContext,
+    ) -> anyhow::Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let (width, height) = (header.width, header.height);
+        let (data, channels) = GeImage::unfilter_main(header, file, options, context)?;
+        Ok(GeImage::to_image(data, width, height, channels, 0xff))
+    }
+
+    /// Decodes a main image straight into a PNG on `writer`, converting one
+    /// row at a time rather than building an RGBA image and encoding it whole.
+    fn stream_main(
+        file: &mut impl Read,
+        options: &DecodeOptions,
+        context: &mut DecodeContext,
+        writer: impl Write,
+    ) -> anyhow::Result<()> {
+        let header = MainHeader::read(file)?;
+        let (width, height) = (header.width, header.height);
+        let (data, channels) = GeImage::unfilter_main(header, file, options, context)?;
+        let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+        encoder.set_color(match channels {
+            4 => png::ColorType::Rgba,
+            _ => png::ColorType::Rgb,
+        });
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let mut stream = writer.stream_writer()?;
+        let mut row = Vec::with_capacity(width * channels);
+        for pixels in data.chunks_exact((width * channels).max(1)).take(height) {
+            row.clear();
+            for pixel in pixels.chunks_exact(channels) {
+                row.extend([pixel[2], pixel[1], pixel[0]]);
+                row.extend(&pixel[3..]);
+            }
+            stream.write_all(&row)?;
+        }
+        stream.finish()?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    fn unfilter_main<'a>(
+        header: MainHeader,
+        file: &mut impl Read,
+        options: &DecodeOptions,
+        context: &'a mut DecodeContext,
+    ) -> anyhow::Result<(&'a [u8], usize)> {
+        let mut channels = header.channels();
+        let MainHeader {
+            width,
+            height,
+            filter_type,
+            size_orig,
+            size_comp,
+            ..
+        } = header;
+        GeImage::check_dimensions(width, height, options)?;
+        let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+        context.read_payload(file, size_comp, size_orig, options, deadline)?;
+        let data = &mut context.data;
+        if filter_type == 3 {
+            options.require("delta header", data, 8)?;
+            channels = GeImage::channels(u16::from_le_bytes([data[2], data[3]]))?;
+            data.drain(..8);
+        }
+        GeImage::unfilter(
+            filter_type,
+            context,
+            width,
+            height,
+            channels,
+            options,
+            deadline,
+        )
+    }
+
+    /// Checks that a filter_type 2 payload holds its planes: chroma, chroma and
+    /// luma, plus alpha when `channels` is 4.
+    fn check_yuv(
+        data: &mut Vec<u8>,
+        width: usize,
+        height: usize,
+        channels: usize,
+        options: &DecodeOptions,
+    ) -> anyhow::Result<()> {
+        if !width.is_multiple_of(2) || !height.is_multiple_of(2) {
+            anyhow::bail!("filter_type 2 requires even dimensions, got {width}x{height}");
+        }
+        options.require("image data", data, width * height * (channels * 2 - 3) / 2)
+    }
+
+    /// Decodes only the `(x, y, width, height)` rectangle of a main image.
+    /// The LZ payload can't be decompressed partially, so all of it is read;
+    /// filter_type 2 then converts only the blocks covering the rectangle,
+    /// while filter_type 3 deltas chain across rows and columns and are undone
+    /// in full, with only the pixel assembly scoped to the rectangle.
+    fn decode_main_region(
+        file: &mut impl Read,
+        (x, y, width, height): (usize, usize, usize, usize),
+        options: &DecodeOptions,
+    ) -> anyhow::Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let header = MainHeader::read(file)?;
+        let (full_width, full_height) = (header.width, header.height);
+        if width == 0 || height == 0 || x + width > full_width || y + height > full_height {
+            anyhow::bail!(
+                "region {width}x{height}+{x}+{y} is outside the {full_width}x{full_height} image"
+            );
+        }
+        let mut context = DecodeContext::default();
+        if header.filter_type == 2 {
+            GeImage::check_dimensions(full_width, full_height, options)?;
+            let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+            context.read_payload(file, header.size_comp, header.size_orig, options, deadline)?;
+            let data = &mut context.data;
+            let channels = header.channels();
+            GeImage::check_yuv(data, full_width, full_height, channels, options)?;
+            let output = &mut context.pixels;
+            output.clear();
+            output.resize(width * height * channels, 0);
+            let size = full_width * full_height;
+            for block_y in y >> 1..(y + height).div_ceil(2) {
+                for block_x in x >> 1..(x + width).div_ceil(2) {
+                    let chroma = block_y * (full_width >> 1) + block_x;
+                    let b = 226 * data[chroma] as i8 as i32;
+                    let g = -43 * data[chroma] as i8 as i32
+                        - 89 * data[chroma + (size >> 2)] as i8 as i32;
+                    let r = 179 * data[chroma + (size >> 2)] as i8 as i32;
+                    for (px, py) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let (px, py) = (block_x * 2 + px, block_y * 2 + py);
+                        if !(x..x + width).contains(&px) || !(y..y + height).contains(&py) {
+                            continue;
+                        }
+                        let luma = (size >> 1) + py * full_width + px;
+                        let base = (data[luma] as i32) << 7;
+                        let pos = ((py - y) * width + px - x) * channels;
+                        output[pos] = ((base + b) >> 7).clamp(0, 255) as u8;
+                        output[pos + 1] = ((base + g) >> 7).clamp(0, 255) as u8;
+                        output[pos + 2] = ((base + r) >> 7).clamp(0, 255) as u8;
+                        if channels == 4 {
+                            output[pos + 3] = data[luma + size];
+                        }
+                    }
+                }
+            }
+            return Ok(GeImage::to_image(output, width, height, channels, 0xff));
+        }
+        let (data, channels) = GeImage::unfilter_main(header, file, options, &mut context)?;
+        let stride = full_width * channels;
+        let region = (y..y + height)
+            .flat_map(|row| {
+                &data[row * stride + x * channels..row * stride + (x + width) * channels]
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        Ok(GeImage::to_image(&region, width, height, channels, 0xff))
+    }
+
+    /// Undoes `filter_type` on decompressed data, returning interleaved BGR(A)
+    /// pixels and their channel count.
+    fn unfilter<'a>(
+        filter_type: u16,
+        context: &'a mut DecodeContext,
+        width: usize,
+        height: usize,
+        channels: usize,
+        options: &DecodeOptions,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<(&'a [u8], usize)> {
+        let data = &mut context.data;
+        match filter_type {
+            2 => {
+                GeImage::check_yuv(data, width, height, channels, options)?;
+                GeImage::apply_filter(data, &mut context.pixels, width, height, channels);
+                Ok((&context.pixels, channels))
+            }
+            3 => {
+                if !matches!(channels, 3 | 4) {
+                    return Err(
+                        Unsupported(format!("unsupported channel count: {channels}")).into(),
+                    );
+                }
+                options.require("image data", data, height + width * height * channels)?;
+                let (deltas, pixels) = data.split_at_mut(height);
+                GeImage::apply_delta_filter(pixels, deltas, width, height, channels, deadline)?;
+                Ok((pixels, channels))
+            }
+            filter_type => {
+                Err(Unsupported(format!("unsupported filter_type {filter_type}")).into())
+            }
+        }
+    }
+
+    /// Builds an image from interleaved BGR(A) pixels, using `alpha` when the
+    /// data has no alpha channel.
+    fn to_image(
+        data: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+        alpha: u8,
+    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let mut image = ImageBuffer::new(0, 0);
+        GeImage::put_rows(data, width, height, channels, alpha, &mut image);
+        image
+    }
+
+    /// Feeds interleaved BGR(A) pixels to `sink` as RGBA rows.
+    fn put_rows(
+        data: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+        alpha: u8,
+        sink: &mut impl PixelSink,
+    ) {
+        sink.begin(width as u32, height as u32);
+        let mut row = Vec::with_capacity(width * 4);
+        for (y, pixels) in data
+            .chunks_exact((width * channels).max(1))
+            .take(height)
+            .enumerate()
+        {
+            row.clear();
+            for pixel in pixels.chunks_exact(channels) {
+                row.extend([
+                    pixel[2],
+                    pixel[1],
+                    pixel[0],
+                    *pixel.get(3).unwrap_or(&alpha),
+                ]);
+            }
+            sink.put_row(y as u32, &row);
+        }
+    }
+
+    /// Decodes a main image from a reader positioned right after the magic
+    /// into `sink`, without building an image.
+    fn decode_main_into(
+        file: &mut impl Read,
+        options: &DecodeOptions,
+        context: &mut DecodeContext,
+        sink: &mut impl PixelSink,
+    ) -> anyhow::Result<()> {
+        let header = MainHeader::read(file)?;
+        let (width, height) = (header.width, header.height);
+        let (data, channels) = GeImage::unfilter_main(header, file, options, context)?;
+        GeImage::put_rows(data, width, height, channels, 0xff, sink);
+        Ok(())
+    }
+
+    /// Decodes a sub image from a reader positioned right after the magic.
+    #[cfg(test)]
+    fn decode_sub(
+        file: &mut impl Read,
+        images: &HashMap<Arc<String>, ImageBuffer<Rgba<u8>, Vec<u8>>>,
+        options: &DecodeOptions,
+    ) -> anyhow::Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        GeImage::decode_sub_with(file, images, options, &mut DecodeContext::default())
+    }
+
+    fn decode_sub_with(
+        file: &mut impl Read,
+        images: &HashMap<Arc<String>, ImageBuffer<Rgba<u8>, Vec<u8>>>,
+        options: &DecodeOptions,
+        context: &mut DecodeContext,
+    ) -> anyhow::Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let header = SubHeader::read(file)?;
+        GeImage::decode_sub_payload(header, file, images, options, context)
+    }
+
+    /// Decodes the payload following an already read sub header.
+    fn decode_sub_payload(
+        header: SubHeader,
+        file: &mut impl Read,
+        images: &HashMap<Arc<String>, ImageBuffer<Rgba<u8>, Vec<u8>>>,
+        options: &DecodeOptions,
+        context: &mut DecodeContext,
+    ) -> anyhow::Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let SubHeader {
+            x,
+            y,
+            width,
+            height,
+            channels,
+            base,
+            size_orig,
+            size_comp,
+            ..
+        } = header;
+        GeImage::check_dimensions(width, height, options)?;
+        let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+        context.read_payload(file, size_comp, size_orig, options, deadline)?;
+        let expected = height + width * height * channels;
+        if context.data.len() != expected {
+            options.clamp(format!(
+                "sub data is {:#x} bytes but {width}x{height}x{channels} needs {expected:#x}",
+                context.data.len(),
+            ))?;
+        }
+        let (data, channels) = GeImage::unfilter(
+            SubHeader::FILTER_TYPE,
+            context,
+            width,
+            height,
+            channels,
+            options,
+            deadline,
+        )?;
+        if options.flatten_subs {
+            return Ok(GeImage::to_image(data, width, height, channels, 0xff));
+        }
+        let alpha = match options.blend {
+            Blend::Xor => 0,
+            Blend::Over => 0xff,
+        };
+        let sub = GeImage::to_image(data, width, height, channels, alpha);
+        let (mut image, x, y) = if base.trim().is_empty() {
+            (ImageBuffer::new(width as u32, height as u32), 0, 0)
+        } else {
+            let image = images
+                .get(&base)
+                .ok_or_else(|| anyhow::anyhow!("base image not found: {base}"))?;
+            (image.clone(), x, y)
+        };
+        if x as usize + width > image.width() as usize
+            || y as usize + height > image.height() as usize
+        {
+            options.clamp(format!(
+                "sub image {width}x{height}+{x}+{y} exceeds base {}x{}",
+                image.width(),
+                image.height(),
+            ))?;
+        }
+        for (_x, _y, pixel) in sub.enumerate_pixels() {
+            if let Some(base) = image.get_pixel_mut_checked(_x + x, _y + y) {
+                match options.blend {
+                    Blend::Xor => base.apply2(pixel, BitXor::bitxor),
+                    Blend::Over => Blend::over(base, pixel),
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+/// Receives decoded pixels one RGBA row at a time, top to bottom.
+pub trait PixelSink {
+    /// Called once with the image dimensions before any row.
+    fn begin(&mut self, _width: u32, _height: u32) {}
+
+    fn put_row(&mut self, y: u32, rgba: &[u8]);
+}
+
+impl PixelSink for ImageBuffer<Rgba<u8>, Vec<u8>> {
+    fn begin(&mut self, width: u32, height: u32) {
+        *self = ImageBuffer::new(width, height);
+    }
+
+    fn put_row(&mut self, y: u32, rgba: &[u8]) {
+        let start = y as usize * self.width() as usize * 4;
+        (**self)[start..start + rgba.len()].copy_from_slice(rgba);
+    }
+}
+
+/// Converts filter_type 2 planes to BGR, exposed for the benchmarks.
+#[doc(hidden)]
+pub fn apply_filter(data: &[u8], output: &mut Vec<u8>, width: usize, height: usize) {
+    GeImage::apply_filter(data, output, width, height, 3);
+}
+
+/// Decompresses a raw payload strictly, exposed for the golden tests.
+#[doc(hidden)]
+pub fn decompress(input: &[u8], size_orig: usize) -> anyhow::Result<Vec<u8>> {
+    GeImage::decompress(input, size_orig, &DecodeOptions::default(), None)
+}
+
+#[derive(Clone, Copy)]
+struct PacLayout {
+    directory: u64,
+    name_len: usize,
+}
+
+impl PacLayout {
+    const LEGACY: Self = Self {
+        directory: 0x0804,
+        name_len: 32,
+    };
+    const VERSIONS: &[Self] = &[Self::LEGACY];
+    /// End of the optional u32 directory offset stored right after the count.
+    const OFFSET_FIELD_END: u64 = 0x14;
+
+    /// Prefers a directory right after a variable-length header, whose length
+    /// is stored after the count. Legacy archives may hold anything in those
+    /// bytes, so that layout is only taken when the length points between the
+    /// header and the legacy directory and every record there is plausible;
+    /// otherwise the known fixed layouts are used.
+    fn detect(
+        pac: &mut impl ReadSeek,
+        count: u64,
+        size: u64,
+        directory: u64,
+        encoding: &'static Encoding,
+    ) -> anyhow::Result<Self> {
+        let header = Self {
+            directory,
+            ..Self::LEGACY
+        };
+        if (Self::OFFSET_FIELD_END..Self::LEGACY.directory).contains(&directory)
+            && header.valid(pac, count, size, encoding)?
+        {
+            return Ok(header);
+        }
+        Self::VERSIONS
+            .iter()
+            .copied()
+            .find(|layout| layout.end(count).is_some_and(|end| end <= size))
+            .ok_or_else(|| anyhow::anyhow!("no known directory layout fits {count} entries"))
+    }
+
+    /// End of a directory of `count` records.
+    fn end(&self, count: u64) -> Option<u64> {
+        count
+            .checked_mul(self.name_len as u64 + 8)?
+            .checked_add(self.directory)
+    }
+
+    /// Whether the directory fits in the file and each record has a name
+    /// that decodes and data between the directory and the end of the file.
+    fn valid(
+        &self,
+        pac: &mut impl ReadSeek,
+        count: u64,
+        size: u64,
+        encoding: &'static Encoding,
+    ) -> io::Result<bool> {
+        let Some(end) = self.end(count).filter(|&end| end <= size) else {
+            return Ok(false);
+        };
+        let mut record = vec![0; self.name_len + 8];
+        pac.seek(SeekFrom::Start(self.directory))?;
+        for _ in 0..count {
+            pac.read_exact(&mut record)?;
+            let (name, fields) = record.split_at(self.name_len);
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            let (name, had_errors) = encoding.decode_without_bom_handling(name);
+            let len = u32::from_le_bytes(fields[..4].try_into().unwrap()) as u64;
+            let offset = u32::from_le_bytes(fields[4..].try_into().unwrap()) as u64;
+            if had_errors
+                || name
+                    .trim_end_matches(|c: char| c.is_control() || c.is_whitespace())
+                    .is_empty()
+                || offset < end
+                || offset + len > size
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Decoded mains shared across archives, keyed by entry content so that a
+/// background duplicated in several PACs decodes once. The least recently used
+/// image goes first once the pixels exceed `capacity` bytes.
+///
+/// Each use stamps the image with a new generation and queues it again, so
+/// `order` keeps stale entries until they reach the front and are skipped.
+#[derive(Default)]
+struct ImageCache {
+    capacity: usize,
+    size: usize,
+    generation: u64,
+    images: HashMap<blake3::Hash, (RgbaImage, u64)>,
+    order: VecDeque<(blake3::Hash, u64)>,
+}
+
+impl ImageCache {
+    fn new(capacity: usize) -> Mutex<Self> {
+        Mutex::new(Self {
+            capacity,
+            ..Default::default()
+        })
+    }
+
+    fn get(&mut self, hash: &blake3::Hash) -> Option<RgbaImage> {
+        let (image, generation) = self.images.get_mut(hash)?;
+        self.generation += 1;
+        *generation = self.generation;
+        let image = image.clone();
+        self.touch(*hash);
+        Some(image)
+    }
+
+    fn insert(&mut self, hash: blake3::Hash, image: &RgbaImage) {
+        let bytes = image.as_raw().len();
+        if bytes > self.capacity || self.images.contains_key(&hash) {
+            return;
+        }
+        while self.size + bytes > self.capacity {
+            let Some((oldest, generation)) = self.order.pop_front() else {
+                break;
+            };
+            if self
+                .images
+                .get(&oldest)
+                .is_some_and(|&(_, g)| g == generation)
+            {
+                self.size -= self
+                    .images
+                    .remove(&oldest)
+                    .map_or(0, |(i, _)| i.as_raw().len());
+            }
+        }
+        self.generation += 1;
+        self.size += bytes;
+        self.images.insert(hash, (image.clone(), self.generation));
+        self.touch(hash);
+    }
+
+    /// Queues `hash` at its current generation, dropping the stale entries
+    /// once they outnumber the live ones.
+    fn touch(&mut self, hash: blake3::Hash) {
+        self.order.push_back((hash, self.generation));
+        if self.order.len() > 2 * self.images.len() + 16 {
+            let images = &self.images;
+            self.order.retain(|(hash, generation)| {
+                images.get(hash).is_some_and(|&(_, g)| g == *generation)
+            });
+        }
+    }
+}
+
+/// Bounds the PAC handles open at once across workers; a handle gives its
+/// slot back when dropped.
+struct FileLimit {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl FileLimit {
+    const DEFAULT: usize = 64;
+
+    /// At least two, as a reader holding the primary may open a split part.
+    fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            available: Mutex::new(limit.max(2)),
+            released: Condvar::new(),
+        })
+    }
+
+    fn open(self: &Arc<Self>, path: &Path, retries: u32) -> io::Result<LimitedFile> {
+        let available = self.available.lock().unwrap();
+        *self.released.wait_while(available, |n| *n == 0).unwrap() -= 1;
+        let file = retry(retries, || File::open(path)).inspect_err(|_| self.release())?;
+        Ok(LimitedFile {
+            file,
+            limit: self.clone(),
+        })
+    }
+
+    fn release(&self) {
+        *self.available.lock().unwrap() += 1;
+        self.released.notify_one();
+    }
+}
+
+struct LimitedFile {
+    file: File,
+    limit: Arc<FileLimit>,
+}
+
+impl Deref for LimitedFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl DerefMut for LimitedFile {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+impl Read for LimitedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for LimitedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for LimitedFile {
+    fn drop(&mut self) {
+        self.limit.release();
+    }
+}
+
+/// Where a PAC's bytes are read from: its file on disk, or the reader it was
+/// parsed from by `PacData::from_reader`.
+enum Source<'a> {
+    File(LimitedFile),
+    Reader(&'a Mutex<Box<dyn ReadSeek + Send>>),
+}
+
+impl Source<'_> {
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            Source::File(file) => Ok(file.metadata()?.len()),
+            Source::Reader(reader) => reader.lock().unwrap().seek(SeekFrom::End(0)),
+        }
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match self {
+            Source::File(file) => file.read_exact_at(buf, offset),
+            Source::Reader(reader) => {
+                let mut reader = reader.lock().unwrap();
+                reader.seek(SeekFrom::Start(offset))?;
+                reader.read_exact(buf)
+            }
+        }
+    }
+}
+
+/// Runs `op`, retrying up to `retries` times with exponential backoff while it
+/// fails with an error kind that may clear up on its own.
+fn retry<T>(retries: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e)
+                if attempt < retries
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::Interrupted
+                            | io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                thread::sleep(Duration::from_millis(50 << attempt.min(8)));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sniffs `data` as text: no NULs and no control bytes besides tab, line
+/// breaks, form feed and escape. Multi-byte encodings such as Shift-JIS pass.
+fn is_text(data: &[u8]) -> bool {
+    !data.is_empty()
+        && data
+            .iter()
+            .all(|&b| b >= 0x20 || matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+}
+
+/// Converts CRLF and lone CR line endings to LF.
+fn normalize_newlines(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut bytes = data.iter().peekable();
+    while let Some(&b) = bytes.next() {
+        if b == b'\r' {
+            bytes.next_if_eq(&&b'\n');
+            output.push(b'\n');
+        } else {
+            output.push(b);
+        }
+    }
+    output
+}
+
+pub struct PacData {
+    name: String,
+    source: PathBuf,
+    metadata: HashMap<Arc<String>, (u64, usize)>,
+    names: HashMap<Arc<String>, String>,
+    /// Name fields that did not decode cleanly, as stored.
+    raw_names: HashMap<Arc<String>, Vec<u8>>,
+    order: Vec<Arc<String>>,
+    /// Each name's position in `order`.
+    index: HashMap<Arc<String>, usize>,
+    layout: PacLayout,
+    encoding: &'static Encoding,
+    reader: Option<Mutex<Box<dyn ReadSeek + Send>>>,
+    parts: Vec<PathBuf>,
+    retries: u32,
+    files: Arc<FileLimit>,
+}
+
+impl PacData {
+    const MAGIC: [u8; 4] = [0x50, 0x41, 0x43, 0x20];
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+    fn new(name: &str, encoding: &'static Encoding) -> Self {
+        Self {
+            name: name.into(),
+            source: name.into(),
+            metadata: HashMap::new(),
+            names: HashMap::new(),
+            raw_names: HashMap::new(),
+            order: vec![],
+            index: HashMap::new(),
+            layout: PacLayout::LEGACY,
+            encoding,
+            reader: None,
+            parts: vec![],
+            retries: 0,
+            files: FileLimit::new(FileLimit::DEFAULT),
+        }
+    }
+
+    /// Parses the directory from `reader` and keeps it to read entries later.
+    pub fn from_reader<R: Read + Seek + Send + 'static>(
+        name: &str,
+        mut reader: R,
+    ) -> anyhow::Result<Self> {
+        let mut pac = Self::new(name, encoding_rs::SHIFT_JIS).read_directory(&mut reader)?;
+        pac.reader = Some(Mutex::new(Box::new(reader)));
+        Ok(pac)
+    }
+
+    pub fn entry(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        self.read_entry(&self.open_source()?, name)
+    }
+
+    fn open(
+        path: &str,
+        auto_decompress: bool,
+        encoding: &'static Encoding,
+        retries: u32,
+        files: Arc<FileLimit>,
+    ) -> anyhow::Result<Self> {
+        let mut pac = Self::new(path, encoding);
+        pac.retries = retries;
+        pac.files = files;
+        if auto_decompress {
+            pac = pac.decompress()?;
+        }
+        pac.parts = Self::parts(Path::new(path));
+        pac.build()
+    }
+
+    /// Split sets are assumed to name their continuation files `<stem>_01.pac`,
+    /// `<stem>_02.pac`, ... next to the primary, with entry offsets counting
+    /// through the primary and then each part in turn as if concatenated.
+    fn parts(path: &Path) -> Vec<PathBuf> {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        (1..)
+            .map(|index| path.with_file_name(format!("{stem}_{index:02}.pac")))
+            .take_while(|part| part.is_file())
+            .collect()
+    }
+
+    fn is_part(path: &Path) -> bool {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        stem.rsplit_once('_').is_some_and(|(primary, index)| {
+            index.len() == 2
+                && index.bytes().all(|b| b.is_ascii_digit())
+                && path.with_file_name(format!("{primary}.pac")).is_file()
+        })
+    }
+
+    /// Entry names with their offsets, in directory order or as `--sort` asks.
+    fn sorted(&self, order: EntryOrder) -> Vec<(&Arc<String>, u64)> {
+        let mut entries = self
+            .order
+            .iter()
+            .map(|name| (name, self.metadata[name]))
+            .collect::<Vec<_>>();
+        match order {
+            EntryOrder::Archive => {}
+            EntryOrder::Name => entries.sort_by(|a, b| a.0.cmp(b.0)),
+            EntryOrder::Offset => entries.sort_by_key(|&(_, (offset, _))| offset),
+            EntryOrder::Size => entries.sort_by_key(|&(_, (_, len))| len),
+        }
+        entries
+            .into_iter()
+            .map(|(name, (offset, _))| (name, offset))
+            .collect()
+    }
+
+    fn open_source(&self) -> io::Result<Source<'_>> {
+        match &self.reader {
+            Some(reader) => Ok(Source::Reader(reader)),
+            None => Ok(Source::File(self.files.open(&self.source, self.retries)?)),
+        }
+    }
+
+    fn decompress(mut self) -> anyhow::Result<Self> {
+        let mut file = self.files.open(Path::new(&self.name), self.retries)?;
+        let mut b2 = [0; 2];
+        file.read_exact(&mut b2)?;
+        file.rewind()?;
+        let mut reader: Box<dyn Read> = match b2 {
+            Self::GZIP_MAGIC => Box::new(GzDecoder::new(file)),
+            [0x78, _] if u16::from_be_bytes(b2) % 31 == 0 => Box::new(ZlibDecoder::new(file)),
+            _ => return Ok(self),
+        };
+        let name = Path::new(&self.name).file_name().unwrap_or_default();
+        self.source = env::temp_dir().join(format!(
+            "cratri_unpac-{}-{}",
+            process::id(),
+            name.to_string_lossy(),
+        ));
+        io::copy(&mut reader, &mut File::create(&self.source)?)?;
+        Ok(self)
+    }
+
+    fn build(self) -> anyhow::Result<Self> {
+        let mut pac = self.files.open(&self.source, self.retries)?;
+        self.read_directory(&mut pac)
+    }
+
+    fn read_directory(mut self, pac: &mut impl ReadSeek) -> anyhow::Result<Self> {
+        let size = pac.seek(SeekFrom::End(0))?;
+        let mut b4 = [0; 4];
+        let mut b8 = [0; 8];
+        pac.rewind()?;
+        pac.read_exact(&mut b4)?;
+        if Self::MAGIC.eq(&b4) {
+            pac.seek(SeekFrom::Start(8))?;
+            pac.read_exact(&mut b8)?;
+            let count = u64::from_le_bytes(b8);
+            let directory = pac
+                .read_exact(&mut b4)
+                .map_or(0, |()| u32::from_le_bytes(b4) as u64);
+            let layout = PacLayout::detect(pac, count, size, directory, self.encoding)?;
+            self.layout = layout;
+            let mut name = vec![0; layout.name_len];
+            let mut pac = BufReader::new(pac);
+            pac.seek(SeekFrom::Start(layout.directory))?;
+            for _ in 0..count {
+                pac.read_exact(&mut name)?;
+                let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                let raw = &name[..end];
+                let (decoded, had_errors) = self.encoding.decode_without_bom_handling(raw);
+                let name = decoded
+                    .trim_end_matches(|c: char| c.is_control() || c.is_whitespace())
+                    .to_owned();
+                let unusable = had_errors || name.is_empty() || name.contains(char::is_control);
+                let raw = unusable.then(|| raw.to_vec());
+                pac.read_exact(&mut b4)?;
+                let len = u32::from_le_bytes(b4) as usize;
+                pac.read_exact(&mut b4)?;
+                let offset = u32::from_le_bytes(b4) as u64;
+                let mut original = name.clone();
+                for index in 1.. {
+                    if !self.metadata.contains_key(&original.to_lowercase()) {
+                        break;
+                    }
+                    original = match name.rsplit_once('.') {
+                        Some((stem, extension)) => format!("{stem}.{index}.{extension}"),
+                        None => format!("{name}.{index}"),
+                    };
+                }
+                let key = Arc::new(original.to_lowercase());
+                self.metadata.insert(key.clone(), (offset, len));
+                self.names.insert(key.clone(), original);
+                if let Some(raw) = raw {
+                    self.raw_names.insert(key.clone(), raw);
+                }
+                self.index.insert(key.clone(), self.order.len());
+                self.order.push(key);
+            }
+        }
+        Ok(self)
+    }
+
+    fn locate(&self, name: &str, size: u64) -> anyhow::Result<(u64, usize)> {
+        let &(offset, len) = self
+            .metadata
+            .get(&name.to_lowercase())
+            .ok_or_else(|| anyhow::anyhow!("no such entry: {name}"))?;
+        if offset.checked_add(len as u64).is_none_or(|end| end > size) {
+            anyhow::bail!("entry {name} at {offset:#x}+{len:#x} exceeds file size {size:#x}");
+        }
+        Ok((offset, len))
+    }
+
+    fn read_entry(&self, file: &Source, name: &str) -> anyhow::Result<Vec<u8>> {
+        self.read_head(file, name, usize::MAX)
+    }
+
+    /// Reads at most `max` bytes from the start of an entry, from whichever
+    /// part holds it.
+    fn read_head(&self, file: &Source, name: &str, max: usize) -> anyhow::Result<Vec<u8>> {
+        let size = file.len()?;
+        if let Some(&(offset, len)) = self.metadata.get(&name.to_lowercase())
+            && offset >= size
+            && !self.parts.is_empty()
+        {
+            return self.read_part_entry(name, offset - size, len, max);
+        }
+        let (offset, len) = self.locate(name, size)?;
+        Ok(retry(self.retries, || {
+            let mut data = vec![0; len.min(max)];
+            file.read_exact_at(&mut data, offset)?;
+            Ok(data)
+        })?)
+    }
+
+    /// Parses an image entry's header without reading its payload.
+    fn header(&self, file: &Source, name: &str) -> anyhow::Result<Header> {
+        GeImage::probe(&mut &self.read_head(file, name, GeImage::HEADER_LEN)?[..])
+    }
+
+    fn sub_header(&self, file: &Source, name: &str) -> anyhow::Result<SubHeader> {
+        match self.header(file, name)? {
+            Header::Sub(header) => Ok(header),
+            Header::Main(_) => anyhow::bail!("{name} is not a sub image"),
+        }
+    }
+
+    fn read_part_entry(
+        &self,
+        name: &str,
+        mut offset: u64,
+        len: usize,
+        max: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        for part in &self.parts {
+            let file = self.files.open(part, self.retries)?;
+            let size = file.metadata()?.len();
+            if offset < size {
+                if offset + len as u64 > size {
+                    anyhow::bail!("entry {name} runs past the end of {}", part.display());
+                }
+                return Ok(retry(self.retries, || {
+                    let mut data = vec![0; len.min(max)];
+                    file.read_exact_at(&mut data, offset)?;
+                    Ok(data)
+                })?);
+            }
+            offset -= size;
+        }
+        anyhow::bail!("entry {name} lies beyond the last part of {}", self.name)
+    }
+}
+
+impl PacData {
+    fn images(&self) -> usize {
+        self.metadata
+            .keys()
+            .filter(|name| name.ends_with("pgd"))
+            .count()
+    }
+}
+
+impl fmt::Debug for PacData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PacData")
+            .field("name", &self.name)
+            .field("source", &self.source)
+            .field("entries", &self.metadata.len())
+            .field("images", &self.images())
+            .field("raw", &(self.metadata.len() - self.images()))
+            .finish()
+    }
+}
+
+impl fmt::Display for PacData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} entries ({} images, {} raw)",
+            self.name,
+            self.metadata.len(),
+            self.images(),
+            self.metadata.len() - self.images(),
+        )
+    }
+}
+
+impl Drop for PacData {
+    fn drop(&mut self) {
+        if self.source != Path::new(&self.name) {
+            let _ = fs::remove_file(&self.source);
+        }
+    }
+}
+
+#[derive(Default)]
+struct UnpacState {
+    previous: HashMap<String, String>,
+    current: HashMap<String, String>,
+}
+
+impl UnpacState {
+    const NAME: &str = ".unpac-state";
+
+    fn load(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(Self::NAME);
+        let mut state = Self::default();
+        if fs::exists(&path)? {
+            state.previous = serde_json::from_str(&fs::read_to_string(path)?)?;
+        }
+        Ok(state)
+    }
+
+    fn update(&mut self, key: String, hash: blake3::Hash) -> bool {
+        let hash = hash.to_hex().to_string();
+        let changed = self.previous.get(&key) != Some(&hash);
+        self.current.insert(key, hash);
+        changed
+    }
+
+    fn store(mut self, dir: &Path) -> anyhow::Result<()> {
+        self.previous.extend(self.current);
+        fs::create_dir_all(dir)?;
+        fs::write(
+            dir.join(Self::NAME),
+            serde_json::to_string_pretty(&self.previous)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// Finished `[pac, entry]` pairs for `--resume`, appended one JSON line at a
+/// time so a crash loses at most the line being written.
+struct ResumeLog {
+    done: HashSet<(String, String)>,
+    file: File,
+}
+
+impl ResumeLog {
+    const NAME: &str = ".unpac-resume.jsonl";
+
+    fn open(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(Self::NAME);
+        let mut done = HashSet::new();
+        let mut torn = false;
+        if fs::exists(&path)? {
+            let log = fs::read_to_string(&path)?;
+            done.extend(
+                log.lines()
+                    .filter_map(|line| serde_json::from_str(line).ok()),
+            );
+            torn = !log.is_empty() && !log.ends_with('\n');
+        }
+        fs::create_dir_all(dir)?;
+        let mut file = File::options().create(true).append(true).open(path)?;
+        if torn {
+            writeln!(file)?;
+        }
+        Ok(Self { done, file })
+    }
+
+    fn done(&self, pac: &str, name: &str) -> bool {
+        self.done.contains(&(pac.to_string(), name.to_string()))
+    }
+
+    fn finish(&mut self, pac: &str, name: &str) -> anyhow::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(&(pac, name))?)?;
+        Ok(())
+    }
+}
+
+/// Where extracted files go. `rel_path` is relative to the output directory
+/// and always uses `/` separators.
+pub trait OutputSink {
+    fn write(&mut self, rel_path: &str, bytes: &[u8]) -> anyhow::Result<()>;
+
+    /// Reads a written file back for `--verify-output`, if the sink can.
+    fn read_back(&self, _rel_path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+struct FsSink {
+    root: PathBuf,
+    retries: u32,
+    dedup_hardlink: bool,
+    written: HashMap<blake3::Hash, PathBuf>,
+}
+
+impl FsSink {
+    fn new(root: &str, options: &Options) -> Self {
+        Self {
+            root: root.into(),
+            retries: options.retries,
+            dedup_hardlink: options.dedup_hardlink,
+            written: HashMap::new(),
+        }
+    }
+}
+
+impl OutputSink for FsSink {
+    fn write(&mut self, rel_path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let path = self.root.join(rel_path);
+        let retries = self.retries;
+        if let Some(parent) = path.parent() {
+            retry(retries, || fs::create_dir_all(parent))?;
+        }
+        if self.dedup_hardlink {
+            // an earlier run may have linked this path to another output, so
+            // writing through it would change that file too
+            if fs::exists(&path)? {
+                fs::remove_file(&path)?;
+            }
+            let hash = blake3::hash(bytes);
+            if let Some(first) = self.written.get(&hash) {
+                if fs::hard_link(first, &path).is_err() {
+                    retry(retries, || fs::write(&path, bytes))?;
+                }
+                return Ok(());
+            }
+            self.written.insert(hash, path.clone());
+        }
+        retry(retries, || fs::write(&path, bytes))?;
+        Ok(())
+    }
+
+    fn read_back(&self, rel_path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = self.root.join(rel_path);
+        Ok(Some(retry(self.retries, || fs::read(&path))?))
+    }
+}
+
+impl OutputSink for ZipWriter<File> {
+    fn write(&mut self, rel_path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.start_file(rel_path, SimpleFileOptions::default())?;
+        self.write_all(bytes)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<()> {
+        ZipWriter::finish(*self)?;
+        Ok(())
+    }
+}
+
+impl OutputSink for tar::Builder<File> {
+    fn write(&mut self, rel_path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_cksum();
+        self.append_data(&mut header, rel_path, bytes)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<()> {
+        self.into_inner()?;
+        Ok(())
+    }
+}
+
+/// Collects output in memory; clones share the same files.
+#[derive(Clone, Default)]
+pub struct MemorySink(Arc<Mutex<BTreeMap<String, Vec<u8>>>>);
+
+impl MemorySink {
+    pub fn files(&self) -> BTreeMap<String, Vec<u8>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn write(&mut self, rel_path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.0.lock().unwrap().insert(rel_path.into(), bytes.into());
+        Ok(())
+    }
+
+    fn read_back(&self, rel_path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.0.lock().unwrap().get(rel_path).cloned())
+    }
+}
+
+pub type DecodedImage = (String, u32, u32, Vec<u8>);
+
+#[derive(Clone, Debug)]
+pub struct EntryStats {
+    pub name: String,
+    pub size_comp: usize,
+    pub size_orig: usize,
+    pub decode: Duration,
+    /// Time spent encoding and writing the output image.
+    pub encode: Duration,
+    pub output: usize,
+    /// The main's filter_type; `None` for subs.
+    pub filter: Option<u16>,
+}
+
+impl EntryStats {
+    fn new(pac: &PacData, name: &str, data: &[u8], decode: Duration) -> Self {
+        let (size_orig, filter) = match GeImage::probe(&mut &data[..]) {
+            Ok(Header::Main(h)) => (h.size_orig, Some(h.filter_type)),
+            Ok(Header::Sub(h)) => (h.size_orig, None),
+            Err(_) => (0, None),
+        };
+        Self {
+            name: format!("{}/{name}", pac.name),
+            size_comp: data.len(),
+            size_orig,
+            decode,
+            encode: Duration::ZERO,
+            output: 0,
+            filter,
+        }
+    }
+}
+
+pub struct AssetLoader {
+    name: String,
+    data: Vec<PacData>,
+    options: Options,
+    merged: RefCell<HashMap<PathBuf, blake3::Hash>>,
+    conflicts: RefCell<Vec<(PathBuf, PathBuf)>>,
+    progress: ProgressBar,
+    sink: RefCell<Box<dyn OutputSink>>,
+    /// The `--zip` or `--tar` output of the running save, used instead of
+    /// `sink` and finished when the save completes.
+    archive: RefCell<Option<Box<dyn OutputSink>>>,
+    files: Arc<FileLimit>,
+    images: Mutex<ImageCache>,
+    decoders: HashMap<[u8; 4], DecoderFn>,
+    registered: HashSet<[u8; 4]>,
+    stats: Vec<EntryStats>,
+}
+
+impl AssetLoader {
+    pub fn new(name: &str, options: Options) -> Self {
+        Self {
+            name: name.into(),
+            data: vec![],
+            sink: RefCell::new(Box::new(FsSink::new(name, &options))),
+            archive: RefCell::new(None),
+            files: FileLimit::new(match options.max_open_files {
+                0 => FileLimit::DEFAULT,
+                limit => limit,
+            }),
+            images: ImageCache::new(options.image_cache),
+            options,
+            merged: RefCell::new(HashMap::new()),
+            conflicts: RefCell::new(vec![]),
+            progress: ProgressBar::hidden(),
+            decoders: GeImage::decoders(),
+            registered: HashSet::new(),
+            stats: vec![],
+        }
+    }
+
+    fn log(&self, line: String) {
+        if self.options.quiet {
+            return;
+        }
+        if self.progress.is_hidden() {
+            println!("{line}");
+        } else {
+            self.progress.println(line);
+        }
+    }
+
+    fn trace(&self, level: u8, line: String) {
+        if self.options.verbose >= level {
+            self.log(line);
+        }
+    }
+
+    fn record(&self, stats: &mut Vec<EntryStats>, entry: EntryStats) {
+        self.trace(
+            2,
+            format!(
+                "{}: {} -> {} bytes in {:?}, {} bytes written",
+                entry.name, entry.size_comp, entry.size_orig, entry.decode, entry.output,
+            ),
+        );
+        stats.push(entry);
+    }
+
+    /// The `top` entries that took longest to decode and encode, slowest first.
+    fn slowest(stats: &[EntryStats], top: usize) -> Vec<&EntryStats> {
+        let mut slowest = stats.iter().collect::<Vec<_>>();
+        slowest.sort_by_key(|entry| std::cmp::Reverse(entry.decode + entry.encode));
+        slowest.truncate(top);
+        slowest
+    }
+
+    fn profile(&self, stats: &[EntryStats], top: usize) {
+        for entry in Self::slowest(stats, top) {
+            self.log(format!(
+                "PROFILE: {}: {:?} (decode {:?}, encode {:?}), {} -> {} bytes, {}",
+                entry.name,
+                entry.decode + entry.encode,
+                entry.decode,
+                entry.encode,
+                entry.size_comp,
+                entry.size_orig,
+                entry
+                    .filter
+                    .map_or("sub".into(), |filter| format!("filter_type {filter}")),
+            ));
+        }
+    }
+
+    fn warn(&self, line: String) {
+        self.progress.suspend(|| eprintln!("WARNING: {line}"));
+    }
+
+    fn fail(&self, e: anyhow::Error) {
+        self.progress.suspend(|| eprintln!("FAILED: {e:#}"));
+    }
+
+    fn entry_path(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+        let mut path = dir.to_path_buf();
+        for component in name.split(['/', '\\']) {
+            match component {
+                "" | "." => {}
+                ".." => anyhow::bail!("entry name escapes output directory: {name}"),
+                _ => path.push(component),
+            }
+        }
+        Ok(path)
+    }
+
+    /// The name to write `name` under: as stored in the archive with
+    /// `--preserve-case`, otherwise the lowercased lookup key. With
+    /// `--index-names`, names that did not decode or would not make a path
+    /// become `entry_<index>.<ext>` in directory order.
+    fn output_name<'a>(&self, pac: &'a PacData, name: &'a str) -> Cow<'a, str> {
+        if self.options.index_names && Self::renamed(pac, name) {
+            let index = pac.index.get(&name.to_lowercase());
+            let extension = Path::new(name)
+                .extension()
+                .and_then(OsStr::to_str)
+                .filter(|e| {
+                    (1..=4).contains(&e.len()) && e.bytes().all(|b| b.is_ascii_alphanumeric())
+                })
+                .unwrap_or("bin");
+            return Cow::Owned(format!(
+                "entry_{:04}.{extension}",
+                index.copied().unwrap_or_default()
+            ));
+        }
+        match pac.names.get(&name.to_lowercase()) {
+            Some(original) if self.options.preserve_case => Cow::Borrowed(original),
+            _ => Cow::Borrowed(name),
+        }
+    }
+
+    fn renamed(pac: &PacData, name: &str) -> bool {
+        pac.raw_names.contains_key(&name.to_lowercase())
+            || Self::entry_path(Path::new(""), name).is_err()
+    }
+
+    /// `<base stem>__<sub>` in the sub's directory, so every (base, sub)
+    /// composite gets its own file.
+    fn composite_name(base: &str, sub: &str) -> String {
+        let base = Path::new(base)
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        match sub.rsplit_once(['/', '\\']) {
+            Some((dir, sub)) => format!("{dir}/{base}__{sub}"),
+            None => format!("{base}__{sub}"),
+        }
+    }
+
+    /// Where `pac`'s entries go: the output itself with `--merge`.
+    fn pac_dir(&self, pac: &PacData) -> PathBuf {
+        match self.options.merge {
+            true => PathBuf::from(&self.name),
+            false => Path::new(&self.name).join(&pac.name),
+        }
+    }
+
+    fn image_path(&self, dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+        let mut path = Self::entry_path(dir, name)?;
+        let extension = self.options.format.extension();
+        if self.options.append_extension {
+            path.as_mut_os_string().push(format!(".{extension}"));
+        } else {
+            path.set_extension(extension);
+        }
+        Ok(path)
+    }
+
+    fn write_file(&self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        let mut path = Cow::Borrowed(path);
+        if self.options.merge {
+            let hash = blake3::hash(data);
+            let mut merged = self.merged.borrow_mut();
+            let original = path.to_path_buf();
+            for index in 1.. {
+                match merged.get(&*path) {
+                    None => break,
+                    Some(&existing) if existing == hash => return Ok(()),
+                    Some(_) => path = Cow::Owned(Self::suffixed(&original, index)),
+                }
+            }
+            if *path != original {
+                self.conflicts
+                    .borrow_mut()
+                    .push((original, path.to_path_buf()));
+            }
+            merged.insert(path.to_path_buf(), hash);
+        }
+        self.output().write(&self.rel_path(&path)?, data)
+    }
+
+    fn output(&self) -> RefMut<'_, Box<dyn OutputSink>> {
+        RefMut::filter_map(self.archive.borrow_mut(), Option::as_mut)
+            .unwrap_or_else(|_| self.sink.borrow_mut())
+    }
+
+    fn rel_path(&self, path: &Path) -> anyhow::Result<String> {
+        Ok(path
+            .strip_prefix(&self.name)?
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(c) => Some(c.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/"))
+    }
+
+    /// Replaces the output backend, which stays in place across saves; `--zip`
+    /// and `--tar` still take precedence.
+    pub fn with_sink(mut self, sink: impl OutputSink + 'static) -> Self {
+        self.sink = RefCell::new(Box::new(sink));
+        self
+    }
+
+    /// Adds an already parsed PAC, such as one from `PacData::from_reader`, to
+    /// the archives `save` extracts.
+    pub fn with_pac(mut self, pac: PacData) -> Self {
+        self.data.push(pac);
+        self
+    }
+
+    /// Decodes `.pgd` entries starting with `magic` through `decoder`, replacing
+    /// any built-in one. Subs (`PGD3`) are always composited onto their base.
+    pub fn register_decoder(mut self, magic: [u8; 4], decoder: DecoderFn) -> Self {
+        self.decoders.insert(magic, decoder);
+        self.registered.insert(magic);
+        self
+    }
+
+    fn suffixed(path: &Path, index: usize) -> PathBuf {
+        let mut name = path.file_stem().unwrap_or_default().to_os_string();
+        name.push(format!(".{index}"));
+        if let Some(extension) = path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        path.with_file_name(name)
+    }
+
+    fn encode_image(&self, image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+        let image = self.options.decode.apply(image);
+        let format = match self.options.format {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Qoi => ImageFormat::Qoi,
+            OutputFormat::Dds => return Ok(OutputFormat::encode_dds(&image)),
+        };
+        if self.options.palette_output
+            && format == ImageFormat::Png
+            && let Some(data) = OutputFormat::encode_indexed(&image)?
+        {
+            return Ok(data);
+        }
+        let mut data = Cursor::new(vec![]);
+        if self.options.rgb_when_opaque && image.pixels().all(|p| p[3] == 0xff) {
+            ImageBuffer::<Rgb<u8>, _>::from_fn(image.width(), image.height(), |x, y| {
+                image.get_pixel(x, y).to_rgb()
+            })
+            .write_to(&mut data, format)?;
+        } else {
+            image.write_to(&mut data, format)?;
+        }
+        Ok(data.into_inner())
+    }
+
+    fn write_image(
+        &self,
+        image: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+        path: &Path,
+    ) -> anyhow::Result<usize> {
+        let encoded = self.encode_image(image)?;
+        self.write_file(path, &encoded)?;
+        if self.options.verify_output {
+            let data = match self.output().read_back(&self.rel_path(path)?)? {
+                Some(data) => Cow::Owned(data),
+                None => Cow::Borrowed(&encoded),
+            };
+            let written = match self.options.format {
+                OutputFormat::Png => image::load_from_memory_with_format(&data, ImageFormat::Png)?
+                    .to_rgba8()
+                    .into_raw(),
+                OutputFormat::Qoi => image::load_from_memory_with_format(&data, ImageFormat::Qoi)?
+                    .to_rgba8()
+                    .into_raw(),
+                OutputFormat::Dds => data.get(128..).unwrap_or_default().to_vec(),
+            };
+            if written != **self.options.decode.apply(image) {
+                anyhow::bail!("written image {path:?} does not match the decoded image");
+            }
+        }
+        Ok(encoded.len())
+    }
+
+    fn write_fallback(
+        &self,
+        pac: &PacData,
+        file: &Source,
+        dir: &Path,
+        name: &str,
+        e: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        let path = Self::entry_path(dir, &self.output_name(pac, name))?;
+        self.write_file(&path, &pac.read_entry(file, name)?)?;
+        self.warn(format!("{e:#}, wrote raw {path:?}"));
+        Ok(())
+    }
+
+    /// `--no-composite`: writes the base (once), the sub's own pixels and the
+    /// composite side by side under `debug/`.
+    fn write_debug(
+        &self,
+        pac: &PacData,
+        file: &Source,
+        name: &str,
+        composite: &RgbaImage,
+        images: &HashMap<Arc<String>, RgbaImage>,
+        bases: &mut HashSet<String>,
+    ) -> anyhow::Result<()> {
+        let data = pac.read_entry(file, name)?;
+        let mut payload = data.get(4..).unwrap_or_default();
+        let header = SubHeader::read(&mut payload)?;
+        let base = header.base.clone();
+        let flat = DecodeOptions {
+            flatten_subs: true,
+            ..self.options.decode.clone()
+        };
+        let context = &mut DecodeContext::default();
+        let own = GeImage::decode_sub_payload(header, &mut payload, images, &flat, context)?;
+        let debug = self.pac_dir(pac).join("debug");
+        let extension = self.options.format.extension();
+        if let Some(image) = images.get(&base)
+            && bases.insert(base.clone())
+        {
+            let path = Self::entry_path(&debug, &base)?.with_extension(extension);
+            self.write_image(image, &path)?;
+        }
+        let path = Self::entry_path(&debug, &self.output_name(pac, name))?;
+        self.write_image(&own, &path.with_extension(format!("sub.{extension}")))?;
+        self.write_image(
+            composite,
+            &path.with_extension(format!("composite.{extension}")),
+        )?;
+        Ok(())
+    }
+
+    fn write_planes(
+        &self,
+        pac: &PacData,
+        file: &Source,
+        name: &str,
+        path: &Path,
+    ) -> anyhow::Result<()> {
+        let data = pac.read_entry(file, name)?;
+        let planes = GeImage::planes(&mut data.get(4..).unwrap_or_default(), &self.options.decode)?;
+        for (plane, width, height, pixels) in planes {
+            let mut encoded = vec![];
+            let mut encoder = png::Encoder::new(&mut encoded, width as u32, height as u32);
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.write_header()?.write_image_data(&pixels)?;
+            self.write_file(&path.with_extension(format!("{plane}.png")), &encoded)?;
+        }
+        Ok(())
+    }
+
+    fn write_sidecar(
+        &self,
+        entry: &Entry,
+        path: &Path,
+        trim: Option<(u32, u32)>,
+    ) -> anyhow::Result<()> {
+        let entry = Entry {
+            trim,
+            ..entry.clone()
+        };
+        let mut path = path.as_os_str().to_owned();
+        path.push(".json");
+        self.write_file(
+            Path::new(&path),
+            serde_json::to_string_pretty(&entry.to_json())?.as_bytes(),
+        )
+    }
+
+    /// Tight bounds of the pixels that are not fully transparent, or `None`
+    /// when there is nothing to trim: opaque, fully transparent or already tight.
+    fn trim_bounds(image: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+        if image.pixels().all(|p| p[3] == 0xff) {
+            return None;
+        }
+        let (mut left, mut top) = image.dimensions();
+        let (mut right, mut bottom) = (0, 0);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if pixel[3] != 0 {
+                (left, top) = (left.min(x), top.min(y));
+                (right, bottom) = (right.max(x + 1), bottom.max(y + 1));
+            }
+        }
+        let bounds = (
+            left,
+            top,
+            right.checked_sub(left)?,
+            bottom.checked_sub(top)?,
+        );
+        (bounds != (0, 0, image.width(), image.height())).then_some(bounds)
+    }
+
+    /// With `--trim`, crops `image` to its bounds and returns the offset of the
+    /// crop for the sidecar.
+    fn trimmed<'a>(&self, image: &'a RgbaImage) -> (Cow<'a, RgbaImage>, Option<(u32, u32)>) {
+        match Self::trim_bounds(image).filter(|_| self.options.trim) {
+            Some((x, y, width, height)) => (
+                Cow::Owned(imageops::crop_imm(image, x, y, width, height).to_image()),
+                Some((x, y)),
+            ),
+            None => (Cow::Borrowed(image), None),
+        }
+    }
+
+    const THUMBNAIL_SIZE: u32 = 160;
+
+    fn thumbnail(&self, image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let image = self.options.decode.apply(image);
+        let (width, height) = image.dimensions();
+        let scale = (Self::THUMBNAIL_SIZE as f32 / width.max(height).max(1) as f32).min(1.0);
+        let width = ((width as f32 * scale) as u32).max(1);
+        let height = ((height as f32 * scale) as u32).max(1);
+        imageops::resize(&*image, width, height, imageops::FilterType::Triangle)
+    }
+
+    /// Tiles thumbnails into a square-ish grid at `path`, with a `.txt` index
+    /// next to it mapping each cell to its entry.
+    fn write_contact_sheet(
+        &self,
+        path: &Path,
+        thumbnails: &[(String, RgbaImage)],
+    ) -> anyhow::Result<()> {
+        let columns = (thumbnails.len() as f64).sqrt().ceil().max(1.0) as u32;
+        let rows = (thumbnails.len() as u32).div_ceil(columns).max(1);
+        let size = Self::THUMBNAIL_SIZE;
+        let mut sheet = ImageBuffer::new(columns * size, rows * size);
+        let mut index = String::new();
+        for (i, (name, thumbnail)) in thumbnails.iter().enumerate() {
+            let (column, row) = (i as u32 % columns, i as u32 / columns);
+            let x = column * size + (size - thumbnail.width()) / 2;
+            let y = row * size + (size - thumbnail.height()) / 2;
+            imageops::overlay(&mut sheet, thumbnail, x as i64, y as i64);
+            index.push_str(&format!("{row},{column}: {name}\n"));
+        }
+        // relative to the output, so that --zip, --tar and custom sinks get it too
+        let path = Path::new(&self.name).join(path);
+        let mut data = Cursor::new(vec![]);
+        sheet.write_to(&mut data, ImageFormat::from_path(&path)?)?;
+        self.write_file(&path, data.get_ref())
+            .with_context(|| format!("failed to write contact sheet {path:?}"))?;
+        self.write_file(&path.with_extension("txt"), index.as_bytes())?;
+        self.log(format!("FINISHED: {path:?}"));
+        Ok(())
+    }
+
+    fn decoder(&self, name: &str, data: &[u8]) -> anyhow::Result<DecoderFn> {
+        let magic = data.get(..4).unwrap_or_default();
+        match data
+            .first_chunk()
+            .and_then(|magic| self.decoders.get(magic))
+        {
+            Some(&decoder) => Ok(decoder),
+            None => Err(Unsupported(format!(
+                "{name}: no decoder registered for magic {magic:02x?}"
+            ))
+            .into()),
+        }
+    }
+
+    /// Decodes image entry `name` with the decoder registered for its magic.
+    fn decode_entry(
+        &self,
+        pac: &PacData,
+        file: &Source,
+        name: &str,
+        images: &HashMap<Arc<String>, RgbaImage>,
+    ) -> anyhow::Result<RgbaImage> {
+        self.decode_data(name, &pac.read_entry(file, name)?, images)
+    }
+
+    fn decode_data(
+        &self,
+        name: &str,
+        data: &[u8],
+        images: &HashMap<Arc<String>, RgbaImage>,
+    ) -> anyhow::Result<RgbaImage> {
+        self.decoder(name, data)?(
+            &mut data.get(4..).unwrap_or_default(),
+            images,
+            &self.options.decode,
+            &mut DecodeContext::default(),
+        )
+    }
+
+    /// Decodes a sub image along with the base it is composited onto.
+    fn decode_sub_entry(
+        &self,
+        pac: &PacData,
+        file: &Source,
+        name: &str,
+    ) -> anyhow::Result<RgbaImage> {
+        let base = pac.sub_header(file, name)?.base;
+        let mut images = HashMap::new();
+        if !base.trim().is_empty() {
+            let data = pac.read_entry(file, &base)?;
+            let hash = (self.options.image_cache > 0).then(|| blake3::hash(&data));
+            let image = match hash.and_then(|h| self.images.lock().unwrap().get(&h)) {
+                Some(image) => image,
+                None => {
+                    let image = self.decode_data(&base, &data, &HashMap::new())?;
+                    if let Some(hash) = hash {
+                        self.images.lock().unwrap().insert(hash, &image);
+                    }
+                    image
+                }
+            };
+            images.insert(Arc::new(base), image);
+        }
+        self.decode_entry(pac, file, name, &images)
+    }
+
+    /// Whether `magic` still decodes with the built-in decoder, which the
+    /// region and streaming paths depend on.
+    fn builtin(&self, magic: &[u8; 4]) -> bool {
+        !self.registered.contains(magic)
+    }
+
+    /// Decodes image entry `name` into `sink`, before any output transforms.
+    /// Mains are fed straight from the unfiltered pixels; subs are composited
+    /// onto their base first.
+    pub fn decode_into(
+        &self,
+        pac: &PacData,
+        name: &str,
+        sink: &mut impl PixelSink,
+    ) -> anyhow::Result<()> {
+        let file = pac.open_source()?;
+        let data = pac.read_entry(&file, name)?;
+        let image = match data.first_chunk() {
+            Some(magic @ &GeImage::MAIN_MAGIC) if self.builtin(magic) => {
+                return GeImage::decode_main_into(
+                    &mut data.get(4..).unwrap_or_default(),
+                    &self.options.decode,
+                    &mut DecodeContext::default(),
+                    sink,
+                );
+            }
+            Some(&GeImage::SUB_MAGIC) => self.decode_sub_entry(pac, &file, name)?,
+            _ => self.decode_entry(pac, &file, name, &HashMap::new())?,
+        };
+        let (width, height) = image.dimensions();
+        sink.begin(width, height);
+        for (y, row) in image.chunks_exact((width as usize * 4).max(1)).enumerate() {
+            sink.put_row(y as u32, row);
+        }
+        Ok(())
+    }
+
+    pub fn cat(&self, pac: &PacData, name: &str) -> anyhow::Result<()> {
+        let file = pac.open_source()?;
+        let data = pac.read_entry(&file, name)?;
+        let data = if name.to_lowercase().ends_with("pgd") {
+            let mut payload = data.get(4..).unwrap_or_default();
+            let main = data.first_chunk() == Some(&GeImage::MAIN_MAGIC)
+                && self.builtin(&GeImage::MAIN_MAGIC);
+            if main && self.options.streams() && self.options.region.is_none() {
+                let result = GeImage::stream_main(
+                    &mut payload,
+                    &self.options.decode,
+                    &mut DecodeContext::default(),
+                    io::stdout().lock(),
+                );
+                for clamp in DecodeOptions::take_clamps() {
+                    self.warn(format!("{name}: {clamp}"));
+                }
+                return result;
+            }
+            let image = match (data.first_chunk(), self.options.region) {
+                (Some(&GeImage::MAIN_MAGIC), Some(region)) if main => {
+                    GeImage::decode_main_region(&mut payload, region, &self.options.decode)?
+                }
+                (Some(&GeImage::SUB_MAGIC), _) => self.decode_sub_entry(pac, &file, name)?,
+                _ => self.decode_entry(pac, &file, name, &HashMap::new())?,
+            };
+            for clamp in DecodeOptions::take_clamps() {
+                self.warn(format!("{name}: {clamp}"));
+            }
+            self.encode_image(&image)?
+        } else {
+            data
+        };
+        io::stdout().lock().write_all(&data)?;
+        Ok(())
+    }
+
+    /// Prints the header of each `.pgd` entry, in directory order.
+    pub fn probe(&self, pac: &PacData) -> anyhow::Result<()> {
+        let file = pac.open_source()?;
+        for name in &pac.order {
+            if name.ends_with("pgd") {
+                println!("{name}: {}", pac.header(&file, name)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints each base image with the subs placed on it, reading only sub
+    /// headers, and flags bases that are not in the archive.
+    pub fn deps(&self, pac: &PacData) -> anyhow::Result<()> {
+        let file = pac.open_source()?;
+        let mut deps = BTreeMap::<_, Vec<_>>::new();
+        for name in pac.metadata.keys() {
+            if name.ends_with("pgd") {
+                let head = pac.read_head(&file, name, 4)?;
+                if head.first_chunk() == Some(&GeImage::SUB_MAGIC) {
+                    let header = pac.sub_header(&file, name)?;
+                    deps.entry(header.base)
+                        .or_default()
+                        .push((name.clone(), header.x, header.y));
+                }
+            }
+        }
+        for (base, mut subs) in deps {
+            subs.sort();
+            if base.trim().is_empty() {
+                println!("(no base):");
+            } else if pac.metadata.contains_key(&base) {
+                println!("{base}:");
+            } else {
+                println!("{base} (missing):");
+            }
+            for (name, x, y) in subs {
+                println!("  {name} at +{x}+{y}");
+            }
+        }
+        Ok(())
+    }
+
+    fn list_unsupported(&self) -> anyhow::Result<()> {
+        for pac in &self.data {
+            let file = pac.open_source()?;
+            let mut entries = pac.metadata.iter().collect::<Vec<_>>();
+            entries.sort_by_key(|&(_, &(offset, _))| offset);
+            for (name, _) in entries {
+                if name.ends_with("pgd") {
+                    match GeImage::unsupported(&mut &pac.read_entry(&file, name)?[..]) {
+                        Ok(Some(reason)) => println!("{}/{name}: {reason}", pac.name),
+                        Ok(None) => {}
+                        Err(e) => println!("{}/{name}: {e:#}", pac.name),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        if self.options.list_unsupported {
+            return self.list_unsupported();
+        }
+        let mut failures = 0;
+        let only_new = self.options.only_new && !self.options.verify_only;
+        let mut state = if only_new {
+            UnpacState::load(Path::new(&self.name))?
+        } else {
+            UnpacState::default()
+        };
+        let mut resume = match self.options.resume && !self.options.verify_only {
+            true => Some(ResumeLog::open(Path::new(&self.name))?),
+            false => None,
+        };
+        if !self.options.quiet && io::stdout().is_terminal() {
+            let total = self
+                .data
+                .iter()
+                .map(|pac| pac.metadata.len())
+                .sum::<usize>();
+            self.progress = ProgressBar::new(total as u64).with_style(
+                ProgressStyle::with_template("{percent:>3}% [{wide_bar}] {pos}/{len} eta {eta}")?,
+            );
+        }
+        if !self.options.verify_only {
+            if let Some(path) = &self.options.zip {
+                *self.archive.get_mut() = Some(Box::new(ZipWriter::new(File::create(path)?)));
+            } else if let Some(path) = &self.options.tar {
+                *self.archive.get_mut() = Some(Box::new(tar::Builder::new(File::create(path)?)));
+            }
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.options.threads)
+            .build()?;
+        let decode = &self.options.decode;
+        let decoders = &self.decoders;
+        // Decoded images are written a few per thread at a time so that peak
+        // memory does not grow with the archive.
+        let batch = pool.current_num_threads() * 4;
+        let images = &self.images;
+        let cache_dir = self.options.cache_dir.as_deref();
+        if let Some(dir) = cache_dir {
+            fs::create_dir_all(dir)?;
+        }
+        let mut checksums = BTreeMap::new();
+        let mut stats = vec![];
+        let mut skipped_subs = 0;
+        let mut thumbnails = vec![];
+        let mut extracted = 0;
+        for pac in &self.data {
+            if self.options.limit_per_pac {
+                extracted = 0;
+            } else if self.options.limited(extracted) {
+                break;
+            }
+            let dir = self.pac_dir(pac);
+            let file = pac.open_source()?;
+            let mut main_images = HashMap::new();
+            let mut mains = vec![];
+            let mut subs = vec![];
+            let mut raw_mains = HashSet::new();
+            let mut timed_out = HashSet::new();
+            // manifest records wait for the CRC until the raw write or decode
+            // has read the entry anyway
+            let mut records = vec![];
+            let mut crcs = HashMap::new();
+            for (name, _) in pac.sorted(self.options.sort) {
+                let pgd = name.ends_with("pgd");
+                let head = match pgd {
+                    true => pac.read_head(&file, name, GeImage::HEADER_LEN)?,
+                    false => vec![],
+                };
+                let mut entry = Entry::probe(&head);
+                if let Some(header) = &entry.header {
+                    self.trace(2, format!("{}/{name}: {header}", pac.name));
+                }
+                if self.options.checksums {
+                    let key = match self.options.index_names && Self::renamed(pac, name) {
+                        true => {
+                            entry.raw_name = Some(
+                                pac.raw_names
+                                    .get(name)
+                                    .map_or(name.as_bytes(), Vec::as_slice)
+                                    .to_vec(),
+                            );
+                            format!("{}/{}", pac.name, self.output_name(pac, name))
+                        }
+                        false => format!("{}/{name}", pac.name),
+                    };
+                    records.push((key, name.clone(), entry.clone()));
+                }
+                if pgd {
+                    match head.first_chunk().copied().unwrap_or_default() {
+                        GeImage::SUB_MAGIC => {
+                            self.progress.inc(1);
+                            if self.options.subs() {
+                                self.progress.inc_length(1);
+                                subs.push((name.clone(), entry));
+                            } else if self.options.no_subimages {
+                                skipped_subs += 1;
+                            }
+                        }
+                        magic if decoders.contains_key(&magic) => {
+                            let resumed = resume.as_ref().is_some_and(|r| r.done(&pac.name, name));
+                            let changed = !resumed
+                                && (!only_new
+                                    || state.update(
+                                        format!("{}/{name}", pac.name),
+                                        blake3::hash(&pac.read_entry(&file, name)?),
+                                    ));
+                            if !self.options.subs() && (!self.options.mains() || !changed) {
+                                self.progress.inc(1);
+                                continue;
+                            }
+                            mains.push((name.clone(), changed, entry));
+                        }
+                        magic if self.options.fallback() && !self.options.verify_only => {
+                            self.progress.inc(1);
+                            let e = Unsupported(format!("unknown magic: {magic:02x?}"));
+                            self.write_fallback(pac, &file, &dir, name, e.into())?;
+                        }
+                        _ => {
+                            if let Err(e) = self.decoder(name, &head) {
+                                if !self.options.verify_only || self.options.fatal(&e) {
+                                    return Err(e);
+                                }
+                                self.progress.inc(1);
+                                self.fail(e);
+                                failures += 1;
+                            }
+                        }
+                    };
+                } else {
+                    self.progress.inc(1);
+                    if self.options.raw()
+                        && !self.options.verify_only
+                        && !self.options.limited(extracted)
+                        && !resume.as_ref().is_some_and(|r| r.done(&pac.name, name))
+                    {
+                        let path = Self::entry_path(&dir, &self.output_name(pac, name))?;
+                        let data = pac.read_entry(&file, name)?;
+                        if self.options.checksums {
+                            crcs.insert(name.clone(), entry.checksum(&data));
+                        }
+                        if only_new
+                            && !state.update(format!("{}/{name}", pac.name), blake3::hash(&data))
+                        {
+                            continue;
+                        }
+                        let data = match self.options.normalize_newlines && is_text(&data) {
+                            true => Cow::Owned(normalize_newlines(&data)),
+                            false => Cow::Borrowed(&data),
+                        };
+                        self.trace(1, format!("raw {name} -> {path:?}"));
+                        self.write_file(&path, &data)?;
+                        self.log(format!("FINISHED: {path:?}"));
+                        if let Some(resume) = &mut resume {
+                            resume.finish(&pac.name, name)?;
+                        }
+                        extracted += 1;
+                    }
+                }
+            }
+            if self.options.limited(extracted) {
+                mains.clear();
+                subs.clear();
+            }
+            for mains in mains.chunks(batch) {
+                let decoded = pool.install(|| {
+                    mains
+                        .par_iter()
+                        .map_init(DecodeContext::default, |context, (name, _, entry)| {
+                            context.cache =
+                                cache_dir.and_then(|dir| DecodeContext::cache_path(dir, pac, name));
+                            let data = pac.read_entry(&file, name)?;
+                            let crc = self.options.checksums.then(|| entry.checksum(&data));
+                            DecodeOptions::take_clamps();
+                            let start = Instant::now();
+                            let hash = (self.options.image_cache > 0).then(|| blake3::hash(&data));
+                            if let Some(image) = hash.and_then(|h| images.lock().unwrap().get(&h)) {
+                                let stats = EntryStats::new(pac, name, &data, start.elapsed());
+                                return Ok((image, stats, vec![], crc));
+                            }
+                            let magic = data.first_chunk().copied().unwrap_or_default();
+                            let image = decoders[&magic](
+                                &mut data.get(4..).unwrap_or_default(),
+                                &HashMap::new(),
+                                decode,
+                                context,
+                            )
+                            .with_context(|| format!("failed to decode {name}"))?;
+                            if let Some(hash) = hash {
+                                images.lock().unwrap().insert(hash, &image);
+                            }
+                            let stats = EntryStats::new(pac, name, &data, start.elapsed());
+                            Ok((image, stats, DecodeOptions::take_clamps(), crc))
+                        })
+                        .collect::<Vec<_>>()
+                });
+                for ((name, changed, entry), image) in mains.iter().cloned().zip(decoded) {
+                    self.progress.inc(1);
+                    let (image, mut entry_stats, clamps, crc) = match image {
+                        Ok(image) => image,
+                        Err(e) if self.options.verify_only && !self.options.fatal(&e) => {
+                            self.fail(e);
+                            failures += 1;
+                            continue;
+                        }
+                        Err(e) if e.downcast_ref::<TimedOut>().is_some() => {
+                            self.warn(format!("{e:#}, skipped"));
+                            timed_out.insert(name);
+                            continue;
+                        }
+                        Err(e)
+                            if self.options.fallback()
+                                && e.downcast_ref::<Unsupported>().is_some() =>
+                        {
+                            if self.options.mains() && changed {
+                                self.write_fallback(pac, &file, &dir, &name, e)?;
+                            }
+                            raw_mains.insert(name);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    if let Some(crc) = crc {
+                        crcs.insert(name.clone(), crc);
+                    }
+                    for clamp in clamps {
+                        self.warn(format!("{name}: {clamp}"));
+                    }
+                    if self.options.verify_only {
+                        self.log(format!("VERIFIED: {name}"));
+                        extracted += 1;
+                    } else if self.options.mains() && changed && !self.options.limited(extracted) {
+                        let path = self.image_path(&dir, &self.output_name(pac, &name))?;
+                        self.trace(1, format!("main {name} -> {path:?}"));
+                        let (trimmed, trim) = self.trimmed(&image);
+                        let start = Instant::now();
+                        entry_stats.output = self.write_image(&trimmed, &path)?;
+                        entry_stats.encode = start.elapsed();
+                        if self.options.sidecar && entry.header.is_some() {
+                            self.write_sidecar(&entry, &path, trim)?;
+                        }
+                        if self.options.dump_planes {
+                            self.write_planes(pac, &file, &name, &path)?;
+                        }
+                        self.log(format!("FINISHED: {path:?}"));
+                        if let Some(resume) = &mut resume {
+                            resume.finish(&pac.name, &name)?;
+                        }
+                        extracted += 1;
+                    }
+                    self.record(&mut stats, entry_stats);
+                    if self.options.contact_sheet.is_some() {
+                        thumbnails.push((format!("{}/{name}", pac.name), self.thumbnail(&image)));
+                    }
+                    if self.options.subs() {
+                        main_images.insert(name, image);
+                    }
+                }
+            }
+            let mut layers = BTreeMap::<_, Vec<_>>::new();
+            let mut debug_bases = HashSet::new();
+            let mut sub_images = vec![];
+            for (name, entry) in subs {
+                if resume.as_ref().is_some_and(|r| r.done(&pac.name, &name)) {
+                    self.progress.inc(1);
+                    continue;
+                }
+                if only_new {
+                    let base = entry.sub().map_or("", |header| &header.base);
+                    let mut hasher = blake3::Hasher::new();
+                    hasher.update(&pac.read_entry(&file, &name)?);
+                    if let Some(hash) = state.current.get(&format!("{}/{base}", pac.name)) {
+                        hasher.update(hash.as_bytes());
+                    }
+                    if !state.update(format!("{}/{name}", pac.name), hasher.finalize()) {
+                        self.progress.inc(1);
+                        continue;
+                    }
+                }
+                sub_images.push((name, entry));
+            }
+            if self.options.limited(extracted) {
+                sub_images.clear();
+            }
+            for sub_images in sub_images.chunks(batch) {
+                if self.options.limited(extracted) {
+                    break;
+                }
+                let decoded = pool.install(|| {
+                    sub_images
+                        .par_iter()
+                        .map_init(DecodeContext::default, |context, (name, entry)| {
+                            context.cache =
+                                cache_dir.and_then(|dir| DecodeContext::cache_path(dir, pac, name));
+                            let data = pac.read_entry(&file, name)?;
+                            let crc = self.options.checksums.then(|| entry.checksum(&data));
+                            DecodeOptions::take_clamps();
+                            let start = Instant::now();
+                            let image = decoders[&GeImage::SUB_MAGIC](
+                                &mut data.get(4..).unwrap_or_default(),
+                                &main_images,
+                                decode,
+                                context,
+                            )
+                            .with_context(|| format!("failed to decode {name}"))?;
+                            let stats = EntryStats::new(pac, name, &data, start.elapsed());
+                            Ok((image, stats, DecodeOptions::take_clamps(), crc))
+                        })
+                        .collect::<Vec<_>>()
+                });
+                for ((name, entry), image) in sub_images.iter().cloned().zip(decoded) {
+                    let base = entry
+                        .sub()
+                        .map(|header| header.base.clone())
+                        .unwrap_or_default();
+                    if self.options.limited(extracted) {
+                        break;
+                    }
+                    self.progress.inc(1);
+                    let (image, mut entry_stats, clamps, crc) = match image {
+                        Ok(image) => image,
+                        Err(e) if self.options.verify_only && !self.options.fatal(&e) => {
+                            self.fail(e);
+                            failures += 1;
+                            continue;
+                        }
+                        Err(e) => {
+                            if e.downcast_ref::<TimedOut>().is_some() || timed_out.contains(&base) {
+                                self.warn(format!("{e:#}, skipped"));
+                                continue;
+                            }
+                            if !self.options.fallback()
+                                || e.downcast_ref::<Unsupported>().is_none()
+                                    && !raw_mains.contains(&base)
+                            {
+                                return Err(e);
+                            }
+                            self.write_fallback(pac, &file, &dir, &name, e)?;
+                            continue;
+                        }
+                    };
+                    if let Some(crc) = crc {
+                        crcs.insert(name.clone(), crc);
+                    }
+                    for clamp in clamps {
+                        self.warn(format!("{name}: {clamp}"));
+                    }
+                    if self.options.verify_only {
+                        self.log(format!("VERIFIED: {name}"));
+                        self.record(&mut stats, entry_stats);
+                        extracted += 1;
+                        continue;
+                    }
+                    if self.options.psd
+                        && let Some(header) = entry.sub().cloned()
+                        && main_images.contains_key(&header.base)
+                    {
+                        let layer = if decode.flatten_subs {
+                            image
+                        } else {
+                            let (width, height) = (header.width as u32, header.height as u32);
+                            imageops::crop_imm(&image, header.x, header.y, width, height).to_image()
+                        };
+                        self.record(&mut stats, entry_stats);
+                        layers.entry(header.base).or_default().push((
+                            name.to_string(),
+                            header.x,
+                            header.y,
+                            layer,
+                        ));
+                        continue;
+                    }
+                    let mut output = self.output_name(pac, &name);
+                    if self.options.export_base_with_subs
+                        && !decode.flatten_subs
+                        && !base.trim().is_empty()
+                    {
+                        output = Cow::Owned(Self::composite_name(&base, &output));
+                    }
+                    let path = self.image_path(&dir, &output)?;
+                    self.trace(1, format!("sub {name} -> {path:?}"));
+                    let (trimmed, trim) = match decode.flatten_subs {
+                        true => self.trimmed(&image),
+                        false => (Cow::Borrowed(&image), None),
+                    };
+                    let start = Instant::now();
+                    entry_stats.output = self.write_image(&trimmed, &path)?;
+                    entry_stats.encode = start.elapsed();
+                    self.record(&mut stats, entry_stats);
+                    if self.options.sidecar && entry.header.is_some() {
+                        self.write_sidecar(&entry, &path, trim)?;
+                    }
+                    if self.options.no_composite {
+                        self.write_debug(
+                            pac,
+                            &file,
+                            &name,
+                            &image,
+                            &main_images,
+                            &mut debug_bases,
+                        )?;
+                    }
+                    self.log(format!("FINISHED: {path:?}"));
+                    if let Some(resume) = &mut resume {
+                        resume.finish(&pac.name, &name)?;
+                    }
+                    extracted += 1;
+                }
+            }
+            for (base, layers) in layers {
+                let path =
+                    Self::entry_path(&dir, &self.output_name(pac, &base))?.with_extension("psd");
+                self.trace(1, format!("psd {base} -> {path:?}"));
+                let data = OutputFormat::encode_psd(&base, &main_images[&base], &layers);
+                self.write_file(&path, &data)?;
+                self.log(format!("FINISHED: {path:?}"));
+                if let Some(resume) = &mut resume {
+                    for (name, ..) in &layers {
+                        resume.finish(&pac.name, name)?;
+                    }
+                }
+                extracted += 1;
+            }
+            for (key, name, mut entry) in records {
+                entry.crc32 = Some(match crcs.get(&name) {
+                    Some(&crc) => crc,
+                    None => entry.checksum(&pac.read_entry(&file, &name)?),
+                });
+                checksums.insert(key, entry.to_json());
+            }
+        }
+        self.progress.finish_and_clear();
+        for (original, path) in self.conflicts.take() {
+            self.warn(format!(
+                "merge conflict: {original:?} differs, wrote {path:?}"
+            ));
+        }
+        if skipped_subs > 0 {
+            self.log(format!(
+                "SKIPPED: {skipped_subs} sub images (--no-subimages)"
+            ));
+        }
+        if let Some(top) = self.options.profile {
+            self.profile(&stats, top);
+        }
+        if let Some(path) = &self.options.contact_sheet {
+            self.write_contact_sheet(path, &thumbnails)?;
+        }
+        if self.options.checksums && !self.options.verify_only {
+            self.write_file(
+                &Path::new(&self.name).join("manifest.json"),
+                serde_json::to_string_pretty(&checksums)?.as_bytes(),
+            )?;
+        }
+        if let Some(archive) = self.archive.get_mut().take() {
+            archive.finish()?;
+        }
+        if only_new {
+            state.store(Path::new(&self.name))?;
+        }
+        self.stats = stats;
+        if failures > 0 {
+            anyhow::bail!("{failures} entries failed to decode");
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &[EntryStats] {
+        &self.stats
+    }
+
+    pub fn decode_all_rgba(&self) -> anyhow::Result<Vec<DecodedImage>> {
+        let mut decoded = vec![];
+        for pac in &self.data {
+            let file = pac.open_source()?;
+            let mut main_images = HashMap::new();
+            let mut sub_images = vec![];
+            for name in pac.metadata.keys() {
+                if name.ends_with("pgd") {
+                    match pac.read_head(&file, name, 4)?.first_chunk() {
+                        Some(&GeImage::SUB_MAGIC) => sub_images.push(name.clone()),
+                        _ => {
+                            let image = self.decode_entry(pac, &file, name, &HashMap::new())?;
+                            main_images.insert(name.clone(), image);
+                        }
+                    }
+                }
+            }
+            let mut images = vec![];
+            for name in sub_images {
+                let image = self.decode_entry(pac, &file, &name, &main_images)?;
+                images.push((name, image));
+            }
+            for clamp in DecodeOptions::take_clamps() {
+                self.warn(format!("{}: {clamp}", pac.name));
+            }
+            for (name, image) in main_images.into_iter().chain(images) {
+                let image = self.options.decode.apply(&image).into_owned();
+                let (width, height) = image.dimensions();
+                decoded.push((
+                    format!("{}/{name}", pac.name),
+                    width,
+                    height,
+                    image.into_raw(),
+                ));
+            }
+        }
+        Ok(decoded)
+    }
+
+    fn encoding(&self) -> &'static Encoding {
+        self.options.name_encoding.unwrap_or(encoding_rs::SHIFT_JIS)
+    }
+
+    pub fn open(&self, path: &str) -> anyhow::Result<PacData> {
+        PacData::open(
+            path,
+            self.options.auto_decompress,
+            self.encoding(),
+            self.options.retries,
+            self.files.clone(),
+        )
+    }
+
+    pub fn diff(&self, a: &str, b: &str) -> anyhow::Result<()> {
+        let a = self.checksums(a)?;
+        let b = self.checksums(b)?;
+        for (name, crc) in &a {
+            match b.get(name) {
+                None => println!("- {name}"),
+                Some(other) if other != crc => println!("M {name}"),
+                Some(_) => {}
+            }
+        }
+        for name in b.keys().filter(|name| !a.contains_key(*name)) {
+            println!("+ {name}");
+        }
+        Ok(())
+    }
+
+    /// Reports which entries repacking `dir` over `pac` would add, modify or
+    /// drop, with their size changes, without writing anything.
+    pub fn plan(&self, pac: &PacData, dir: &str) -> anyhow::Result<()> {
+        let mut files = BTreeMap::new();
+        Self::walk(Path::new(dir), Path::new(dir), &mut files)?;
+        let mut entries = pac.metadata.iter().collect::<Vec<_>>();
+        entries.sort();
+        let (mut kept, mut modified, mut dropped, mut delta) = (0, 0, 0, 0);
+        for (name, &(_, len)) in entries {
+            let len = len as i64;
+            let Some(path) = files.remove(&name.replace('\\', "/")) else {
+                println!("- {name} ({:+})", -len);
+                dropped += 1;
+                delta -= len;
+                continue;
+            };
+            let size = fs::metadata(&path)?.len() as i64;
+            if size != len || fs::read(&path)? != pac.entry(name)? {
+                println!("M {name} ({len} -> {size}, {:+})", size - len);
+                modified += 1;
+                delta += size - len;
+            } else {
+                kept += 1;
+            }
+        }
+        for (name, path) in &files {
+            let size = fs::metadata(path)?.len() as i64;
+            println!("+ {name} ({size:+})");
+            delta += size;
+        }
+        println!(
+            "{kept} kept, {} added, {modified} modified, {dropped} dropped, {delta:+} bytes",
+            files.len(),
+        );
+        Ok(())
+    }
+
+    /// Rebuilds `pac` at `out` from the entries in `dir`, keeping its header,
+    /// name fields and directory order but recomputing every offset and
+    /// length, and reports the entries that changed.
+    pub fn repair(&self, pac: &PacData, dir: &str, out: &str) -> anyhow::Result<()> {
+        let mut files = BTreeMap::new();
+        Self::walk(Path::new(dir), Path::new(dir), &mut files)?;
+        let file = pac.open_source()?;
+        let PacLayout {
+            directory,
+            name_len,
+        } = pac.layout;
+        let record = name_len as u64 + 8;
+        let mut output = vec![0; directory as usize];
+        file.read_exact_at(&mut output, 0)?;
+        let mut offset = directory + pac.order.len() as u64 * record;
+        let mut payloads = vec![];
+        let mut corrected = 0;
+        let align = self.options.align.unwrap_or(1);
+        for (index, name) in pac.order.iter().enumerate() {
+            let aligned = offset.next_multiple_of(align);
+            payloads.push(vec![0; (aligned - offset) as usize]);
+            offset = aligned;
+            let path = files
+                .get(&name.replace('\\', "/"))
+                .ok_or_else(|| anyhow::anyhow!("no source file for {name}"))?;
+            let data = fs::read(path)?;
+            let mut field = vec![0; name_len];
+            file.read_exact_at(&mut field, directory + index as u64 * record)?;
+            output.extend(field);
+            output.extend((data.len() as u32).to_le_bytes());
+            output.extend(
+                u32::try_from(offset)
+                    .context("repaired archive exceeds 4 GiB")?
+                    .to_le_bytes(),
+            );
+            let (old_offset, old_len) = pac.metadata[name];
+            if (old_offset, old_len) != (offset, data.len()) {
+                println!(
+                    "{name}: {old_offset:#x}+{old_len:#x} -> {offset:#x}+{:#x}",
+                    data.len()
+                );
+                corrected += 1;
+            }
+            offset += data.len() as u64;
+            payloads.push(data);
+        }
+        output.extend(payloads.concat());
+        fs::write(out, output)?;
+        println!("{corrected} of {} entries corrected", pac.order.len());
+        Ok(())
+    }
+
+    /// Writes a copy of `pac` to `out` with `entry` holding `data`. Every other
+    /// byte is kept; only the entry's length and the offsets of the entries
+    /// stored after it change.
+    pub fn replace_entry(
+        &self,
+        pac: &PacData,
+        entry: &str,
+        data: &[u8],
+        out: &str,
+    ) -> anyhow::Result<()> {
+        if !pac.parts.is_empty() {
+            anyhow::bail!("replacing entries of a split archive is not supported");
+        }
+        let file = pac.open_source()?;
+        let mut source = vec![0; file.len()? as usize];
+        file.read_exact_at(&mut source, 0)?;
+        let (offset, len) = pac.locate(entry, source.len() as u64)?;
+        let end = offset + len as u64;
+        let PacLayout {
+            directory,
+            name_len,
+        } = pac.layout;
+        let record = name_len as u64 + 8;
+        if directory + pac.order.len() as u64 * record > offset {
+            anyhow::bail!("entry {entry} at {offset:#x} overlaps the directory");
+        }
+        let key = entry.to_lowercase();
+        let mut output = source[..offset as usize].to_vec();
+        for (index, name) in pac.order.iter().enumerate() {
+            let field = (directory + index as u64 * record) as usize + name_len;
+            let (other, _) = pac.metadata[name];
+            if **name == key {
+                let len = u32::try_from(data.len()).context("replacement exceeds 4 GiB")?;
+                output[field..field + 4].copy_from_slice(&len.to_le_bytes());
+            } else if other >= end {
+                let moved = u32::try_from(other + data.len() as u64 - len as u64)
+                    .context("replaced archive exceeds 4 GiB")?;
+                output[field + 4..field + 8].copy_from_slice(&moved.to_le_bytes());
+            }
+        }
+        output.extend(data);
+        output.extend(&source[end as usize..]);
+        fs::write(out, output)?;
+        Ok(())
+    }
+
+    fn walk(root: &Path, dir: &Path, files: &mut BTreeMap<String, PathBuf>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                Self::walk(root, &path, files)?;
+            } else if let Ok(name) = path.strip_prefix(root) {
+                let name = name
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                files.insert(name.to_lowercase(), path);
+            }
+        }
+        Ok(())
+    }
+
+    fn checksums(&self, path: &str) -> anyhow::Result<BTreeMap<String, u32>> {
+        let mut checksums = BTreeMap::new();
+        for pac in self.read_dir(path)? {
+            let file = pac.open_source()?;
+            let name = Path::new(&pac.name).file_name().unwrap_or_default();
+            for entry in pac.metadata.keys() {
+                checksums.insert(
+                    format!("{}/{entry}", name.to_string_lossy()),
+                    crc32fast::hash(&pac.read_entry(&file, entry)?),
+                );
+            }
+        }
+        Ok(checksums)
+    }
+
+    pub fn load(mut self, path: &str) -> anyhow::Result<Self> {
+        let data = self.read_dir(path)?;
+        self.data.extend(data);
+        Ok(self)
+    }
+
+    fn read_dir(&self, path: &str) -> anyhow::Result<Vec<PacData>> {
+        let mut pacs = fs::read_dir(path)?
+            .filter_map(|f| f.ok())
+            .map(|f| f.path())
+            .filter(|p| p.extension() == Some(OsStr::new("pac")) && !PacData::is_part(p))
+            .collect::<Vec<_>>();
+        pacs.sort();
+        if let Some(cutoff) = self.options.newer_than {
+            let mut newer = vec![];
+            for pac in pacs {
+                if fs::metadata(&pac)?.modified()? > cutoff {
+                    newer.push(pac);
+                }
+            }
+            pacs = newer;
+        }
+        let auto_decompress = self.options.auto_decompress;
+        let encoding = self.encoding();
+        let retries = self.options.retries;
+        let files = &self.files;
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.options.io_workers)
+            .build()?
+            .install(|| {
+                pacs.par_iter()
+                    .map(|pac| {
+                        let files = files.clone();
+                        PacData::open(
+                            &pac.to_string_lossy(),
+                            auto_decompress,
+                            encoding,
+                            retries,
+                            files,
+                        )
+                    })
+                    .collect()
+            })
+    }
+}
+
+// lets the shared test helpers name the crate the way integration tests do
+#[cfg(test)]
+extern crate self as cratri_unpac;
+
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{Pgd, TempDir, extract, pac_archive, pac_archive_at, try_extract};
+
+    struct CountingReader<R> {
+        inner: R,
+        reads: Vec<usize>,
+        seeks: usize,
+    }
+
+    impl<R> CountingReader<R> {
+        fn new(inner: R) -> Self {
+            Self {
+                inner,
+                reads: vec![],
+                seeks: 0,
+            }
+        }
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.reads.push(n);
+            Ok(n)
+        }
+    }
+
+    impl<R: Seek> Seek for CountingReader<R> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.seeks += 1;
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn probe_reads_only_header() {
+        let entry = Pgd::main(0x4000, 0x4000, 2)
+            .payload(&vec![0; 0x10000], 0x1800_0000)
+            .build();
+        let mut reader = CountingReader::new(Cursor::new(entry));
+        let Header::Main(header) = GeImage::probe(&mut reader).unwrap() else {
+            panic!("expected a main header");
+        };
+        assert_eq!((header.width, header.height), (0x4000, 0x4000));
+        assert_eq!(reader.reads, [4, MainHeader::SIZE]);
+        assert_eq!(reader.seeks, 0);
+    }
+
+    #[test]
+    fn decode_main_reads_payload_once() {
+        let payload = [0x00, 0x06, 0x80, 0x80, 0x10, 0x20, 0x30, 0x40];
+        let entry = Pgd::main(2, 2, 2).payload(&payload, 6).build();
+        let mut reader = CountingReader::new(Cursor::new(&entry[4..]));
+        let image = GeImage::decode_main(&mut reader, &DecodeOptions::default()).unwrap();
+        assert_eq!(image.dimensions(), (2, 2));
+        assert_eq!(reader.reads, [MainHeader::SIZE, payload.len()]);
+        assert_eq!(reader.seeks, 0);
+    }
+
+    #[test]
+    fn lenient_zero_fills_truncated_payload() {
+        let entry = Pgd::main(2, 2, 2)
+            .payload(&[0x00, 0x06, 0x80, 0x80], 6)
+            .build();
+        assert!(GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).is_err());
+        let options = DecodeOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        DecodeOptions::take_clamps();
+        let image = GeImage::decode_main(&mut &entry[4..], &options).unwrap();
+        assert_eq!(image.dimensions(), (2, 2));
+        assert_eq!(DecodeOptions::take_clamps().len(), 1);
+    }
+
+    #[test]
+    fn no_composite_writes_debug_images() {
+        let main = Pgd::delta(2, 3, &[0], &[1, 2, 3, 4, 5, 6]).build();
+        let sub = Pgd::sub(1, 1, 3)
+            .base("bg.pgd", 1, 0)
+            .data(&[0, 3, 2, 1])
+            .build();
+        let options = Options {
+            no_composite: true,
+            merge: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let files = extract(&[(b"bg.pgd", &main), (b"ev.pgd", &sub)], options);
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [
+                "bg.png",
+                "debug/bg.png",
+                "debug/ev.composite.png",
+                "debug/ev.sub.png",
+                "ev.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn registered_decoders_are_dispatched_by_magic() {
+        let dir = TempDir::with_pac(&[(b"a.pgd", b"TEST\x07"), (b"b.pgd", b"NONE\x07")]);
+        let loader = || {
+            let options = Options {
+                merge: true,
+                quiet: true,
+                ..Default::default()
+            };
+            AssetLoader::new("out", options).with_sink(MemorySink::default())
+        };
+        let error = loader()
+            .register_decoder(*b"TEST", |file, _, _, _| {
+                Ok(ImageBuffer::from_pixel(1, 1, Rgba([file[0], 0, 0, 0xff])))
+            })
+            .load(&dir.to_string_lossy())
+            .unwrap()
+            .save()
+            .unwrap_err();
+        assert!(format!("{error}").contains("no decoder registered for magic [4e, 4f, 4e, 45]"));
+        let sink = MemorySink::default();
+        loader()
+            .with_sink(sink.clone())
+            .register_decoder(*b"TEST", |file, _, _, _| {
+                Ok(ImageBuffer::from_pixel(1, 1, Rgba([file[0], 0, 0, 0xff])))
+            })
+            .register_decoder(*b"NONE", |_, _, _, _| Ok(ImageBuffer::new(1, 1)))
+            .load(&dir.to_string_lossy())
+            .unwrap()
+            .save()
+            .unwrap();
+        let files = sink.files();
+        let image = image::load_from_memory(&files["a.png"]).unwrap().to_rgba8();
+        assert_eq!(image.get_pixel(0, 0).0, [7, 0, 0, 0xff]);
+        assert!(files.contains_key("b.png"));
+    }
+
+    #[test]
+    fn stats_record_filter_and_encode_time() {
+        let main = Pgd::delta(2, 3, &[0], &[1, 2, 3, 4, 5, 6]).build();
+        let sub = Pgd::sub(1, 1, 3)
+            .base("bg.pgd", 1, 0)
+            .data(&[0, 3, 2, 1])
+            .build();
+        let dir = TempDir::with_pac(&[(b"bg.pgd", &main), (b"ev.pgd", &sub)]);
+        let options = Options {
+            profile: Some(1),
+            merge: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let mut loader = AssetLoader::new("out", options)
+            .with_sink(MemorySink::default())
+            .load(&dir.to_string_lossy())
+            .unwrap();
+        loader.save().unwrap();
+        let stats = loader.stats();
+        assert!(stats[0].name.ends_with("data.pac/bg.pgd"));
+        assert_eq!(
+            stats.iter().map(|s| s.filter).collect::<Vec<_>>(),
+            [Some(3), None]
+        );
+        assert!(
+            stats
+                .iter()
+                .all(|s| s.output > 0 && s.encode > Duration::ZERO)
+        );
+        let total = |s: &EntryStats| s.decode + s.encode;
+        let slowest = AssetLoader::slowest(stats, 1);
+        assert_eq!(slowest.len(), 1);
+        assert_eq!(total(slowest[0]), stats.iter().map(total).max().unwrap());
+        let ms = Duration::from_millis;
+        let stats = [(5, 1), (1, 1), (3, 4), (0, 2), (6, 0)].map(|(decode, encode)| EntryStats {
+            decode: ms(decode),
+            encode: ms(encode),
+            ..stats[0].clone()
+        });
+        let slowest = AssetLoader::slowest(&stats, 3);
+        assert_eq!(
+            slowest.iter().map(|&s| total(s)).collect::<Vec<_>>(),
+            [ms(7), ms(6), ms(6)]
+        );
+        assert!(slowest.windows(2).all(|w| total(w[0]) >= total(w[1])));
+        assert_eq!(AssetLoader::slowest(&stats, 10).len(), stats.len());
+    }
+
+    #[test]
+    fn registered_main_decoder_overrides_builtin() {
+        let main = Pgd::delta(2, 3, &[0], &[1, 2, 3, 4, 5, 6]).build();
+        let sub = Pgd::sub(1, 1, 3)
+            .base("bg.pgd", 1, 0)
+            .data(&[0, 3, 2, 1])
+            .build();
+        let dir = TempDir::with_pac(&[(b"bg.pgd", &main), (b"ev.pgd", &sub)]);
+        let loader = AssetLoader::new("out", Options::default())
+            .register_decoder(GeImage::MAIN_MAGIC, |_, _, _, _| {
+                Ok(ImageBuffer::from_pixel(2, 1, Rgba([9, 9, 9, 0xff])))
+            })
+            .load(&dir.to_string_lossy())
+            .unwrap();
+        let pac = &loader.data[0];
+        let mut image = RgbaImage::new(0, 0);
+        loader.decode_into(pac, "bg.pgd", &mut image).unwrap();
+        assert_eq!(image, ImageBuffer::from_pixel(2, 1, Rgba([9, 9, 9, 0xff])));
+        loader.decode_into(pac, "ev.pgd", &mut image).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [9, 9, 9, 0xff]);
+        let decoded = loader.decode_all_rgba().unwrap();
+        assert!(
+            decoded
+                .iter()
+                .all(|(_, _, _, pixels)| pixels[..4] == [9, 9, 9, 0xff])
+        );
+    }
+
+    #[test]
+    fn over_blend_composites_by_alpha() {
+        let base = ImageBuffer::from_pixel(2, 1, Rgba([10, 20, 30, 0xff]));
+        let images = HashMap::from([(Arc::new("bg.pgd".to_string()), base)]);
+        let options = DecodeOptions {
+            blend: Blend::Over,
+            ..Default::default()
+        };
+        let entry = Pgd::sub(2, 1, 4)
+            .base("bg.pgd", 0, 0)
+            .data(&[0, 0, 100, 200, 128, 0, 0, 0xff, 0])
+            .build();
+        let image = GeImage::decode_sub(&mut &entry[4..], &images, &options).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [105, 60, 15, 0xff]);
+        assert_eq!(image.get_pixel(1, 0).0, [10, 20, 30, 0xff]);
+        let entry = Pgd::sub(1, 1, 3)
+            .base("bg.pgd", 1, 0)
+            .data(&[0, 1, 2, 3])
+            .build();
+        let image = GeImage::decode_sub(&mut &entry[4..], &images, &options).unwrap();
+        assert_eq!(image.get_pixel(1, 0).0, [3, 2, 1, 0xff]);
+    }
+
+    #[test]
+    fn pgd_builder_entries_decode() {
+        let data = (0..40 * 40 * 3 / 2).map(|i| i as u8).collect::<Vec<_>>();
+        let entry = Pgd::main(40, 40, 2).data(&data).build();
+        assert_eq!(GeImage::decompress_entry(&mut &entry[..]).unwrap(), data);
+        let image = GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).unwrap();
+        assert_eq!(image.dimensions(), (40, 40));
+
+        let entry = Pgd::main(0, 2, 2).data(&[0; 6]).build();
+        let e = GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).unwrap_err();
+        assert_eq!(e.to_string(), "invalid image dimensions: 0x2");
+
+        let base = ImageBuffer::from_pixel(2, 1, Rgba([1, 2, 3, 0xff]));
+        let images = HashMap::from([(Arc::new("bg.pgd".to_string()), base)]);
+        let entry = Pgd::sub(1, 1, 3)
+            .base("bg.pgd", 1, 0)
+            .data(&[0, 3, 2, 1])
+            .build();
+        let image = GeImage::decode_sub(&mut &entry[4..], &images, &DecodeOptions::default());
+        assert_eq!(image.unwrap().get_pixel(1, 0).0, [0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn short_delta_data_is_an_error() {
+        let entry = Pgd::main(1, 4, 3)
+            .payload(&[0x00, 0x02, 0x00, 0x18], 2)
+            .build();
+        let e = GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).unwrap_err();
+        assert_eq!(e.to_string(), "delta header is 0x2 of 0x8 bytes, 0x6 short");
+        let entry = Pgd::main(1, 4, 3)
+            .payload(&[0x00, 0x09, 0, 0, 0x18, 0, 0, 0, 0, 0, 1], 9)
+            .build();
+        let e = GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).unwrap_err();
+        assert_eq!(e.to_string(), "image data is 0x1 of 0x10 bytes, 0xf short");
+    }
+
+    #[test]
+    fn size_comp_past_entry_is_an_error() {
+        let mut entry = Pgd::main(2, 2, 2)
+            .payload(&[0x00, 0x06, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80], 6)
+            .build();
+        entry.truncate(entry.len() - 3);
+        let e = GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "size_comp 0x8 overruns the entry by 0x3 bytes"
+        );
+    }
+
+    #[test]
+    fn stream_main_matches_decode_main() {
+        let entry = Pgd::delta(
+            2,
+            4,
+            &[0, 1],
+            &[1, 2, 3, 4, 5, 6, 7, 8, 1, 1, 1, 1, 2, 2, 2, 2],
+        )
+        .build();
+        let options = DecodeOptions::default();
+        let image = GeImage::decode_main(&mut &entry[4..], &options).unwrap();
+        let mut data = vec![];
+        let mut context = DecodeContext::default();
+        GeImage::stream_main(&mut &entry[4..], &options, &mut context, &mut data).unwrap();
+        let mut reader = png::Decoder::new(Cursor::new(data)).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(pixels, image.into_raw());
+    }
+
+    #[test]
+    fn yuv_alpha_plane() {
+        let planes = [0x00, 0x00, 0x10, 0x20, 0x30, 0x40, 0x00, 0x55, 0xaa, 0xff];
+        let mut payload = vec![0x00, 0x0a];
+        payload.extend(planes);
+        let entry = Pgd::main(2, 2, 2).payload(&payload, 0x0a).build();
+        let image = GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).unwrap();
+        assert_eq!(
+            image.pixels().map(|p| p[3]).collect::<Vec<_>>(),
+            [0x00, 0x55, 0xaa, 0xff]
+        );
+        assert_eq!(image.get_pixel(1, 1).0[..3], [0x40; 3]);
+        let entry = Pgd::main(2, 2, 2)
+            .payload(&[0x00, 0x06, 0, 0, 0x10, 0x20, 0x30, 0x40], 6)
+            .build();
+        let image = GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).unwrap();
+        assert!(image.pixels().all(|p| p[3] == 0xff));
+    }
+
+    #[test]
+    fn planes_split_yuv_payload() {
+        let entry = Pgd::main(2, 2, 2)
+            .payload(&[0x00, 0x06, 1, 2, 3, 4, 5, 6], 6)
+            .build();
+        let planes = GeImage::planes(&mut &entry[4..], &DecodeOptions::default()).unwrap();
+        assert_eq!(
+            planes,
+            [
+                ("plane1", 1, 1, vec![1]),
+                ("plane2", 1, 1, vec![2]),
+                ("plane3", 2, 2, vec![3, 4, 5, 6]),
+            ]
+        );
+        let entry = Pgd::delta(1, 3, &[0], &[0; 3]).build();
+        assert!(
+            GeImage::planes(&mut &entry[4..], &DecodeOptions::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn decode_main_region_matches_crop() {
+        let mut payload = vec![0x00, 0x18];
+        payload.extend((0..0x18).map(|i| i * 11));
+        let yuv = Pgd::main(4, 4, 2).payload(&payload, 0x18).build();
+        let pixels = (0..36).map(|i| i * 7).collect::<Vec<_>>();
+        let delta = Pgd::delta(3, 3, &[0, 1, 2, 4], &pixels).build();
+        for entry in [yuv, delta] {
+            let options = DecodeOptions::default();
+            let full = GeImage::decode_main(&mut &entry[4..], &options).unwrap();
+            let region = (1, 1, 2, 3);
+            let image = GeImage::decode_main_region(&mut &entry[4..], region, &options).unwrap();
+            assert_eq!(image.dimensions(), (2, 3));
+            for (x, y, pixel) in image.enumerate_pixels() {
+                assert_eq!(pixel, full.get_pixel(x + 1, y + 1));
+            }
+        }
+        let entry = Pgd::delta(3, 3, &[0, 1], &pixels[..18]).build();
+        let e =
+            GeImage::decode_main_region(&mut &entry[4..], (2, 0, 2, 1), &DecodeOptions::default());
+        assert_eq!(
+            e.unwrap_err().to_string(),
+            "region 2x1+2+0 is outside the 3x2 image"
+        );
+    }
+
+    #[test]
+    fn pixel_sink_receives_rows() {
+        struct Rows(Vec<(u32, Vec<u8>)>);
+
+        impl PixelSink for Rows {
+            fn put_row(&mut self, y: u32, rgba: &[u8]) {
+                self.0.push((y, rgba.to_vec()));
+            }
+        }
+
+        let entry = Pgd::delta(2, 3, &[0, 0], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]).build();
+        let mut rows = Rows(vec![]);
+        let mut context = DecodeContext::default();
+        let options = DecodeOptions::default();
+        GeImage::decode_main_into(&mut &entry[4..], &options, &mut context, &mut rows).unwrap();
+        assert_eq!(
+            rows.0,
+            [
+                (0, vec![3, 2, 1, 0xff, 6, 5, 4, 0xff]),
+                (1, vec![9, 8, 7, 0xff, 12, 11, 10, 0xff]),
+            ]
+        );
+        let image = GeImage::decode_main(&mut &entry[4..], &options).unwrap();
+        assert_eq!(
+            image.into_raw(),
+            rows.0
+                .into_iter()
+                .flat_map(|(_, row)| row)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn cached_payload_skips_decompression() {
+        let dir = TempDir::new();
+        let archive = pac_archive(&[(b"a.pgd", b"entry")]);
+        let pac = PacData::from_reader("test.pac", Cursor::new(archive)).unwrap();
+        let path = DecodeContext::cache_path(&dir, &pac, "a.pgd").unwrap();
+        let options = DecodeOptions::default();
+        let mut context = DecodeContext {
+            cache: Some(path.clone()),
+            ..Default::default()
+        };
+        context
+            .read_payload(&mut &[0x00, 0x03, 1, 2, 3][..], 5, 3, &options, None)
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [1, 2, 3]);
+        context.cache = Some(path.clone());
+        let mut payload = &[0xff; 5][..];
+        context
+            .read_payload(&mut payload, 5, 3, &options, None)
+            .unwrap();
+        assert_eq!(context.data, [1, 2, 3]);
+        assert!(payload.is_empty());
+        fs::write(&path, [1, 2]).unwrap();
+        context.cache = Some(path.clone());
+        context
+            .read_payload(&mut &[0x00, 0x03, 4, 5, 6][..], 5, 3, &options, None)
+            .unwrap();
+        assert_eq!(context.data, [4, 5, 6]);
+        assert_eq!(fs::read(&path).unwrap(), [4, 5, 6]);
+    }
+
+    #[test]
+    fn decompress_entry_returns_filtered_bytes() {
+        let entry = Pgd::main(2, 2, 2)
+            .payload(&[0x00, 0x06, 1, 2, 3, 4, 5, 6], 6)
+            .build();
+        assert_eq!(
+            GeImage::decompress_entry(&mut &entry[..]).unwrap(),
+            [1, 2, 3, 4, 5, 6]
+        );
+        assert!(GeImage::decompress_entry(&mut &[0; 8][..]).is_err());
+    }
+
+    #[test]
+    fn entry_channels_for_each_filter() {
+        let yuv = Pgd::main(2, 2, 2).data(&[0; 6]).build();
+        let yuva = Pgd::main(2, 2, 2).data(&[0; 10]).build();
+        let delta = Pgd::delta(1, 4, &[0], &[0; 4]).build();
+        let sub = Pgd::sub(1, 1, 3).data(&[0; 4]).build();
+        let unknown = Pgd::main(2, 2, 5).build();
+        let huge = Pgd::main(u32::MAX, u32::MAX, 2)
+            .payload(&[], u32::MAX)
+            .build();
+        for (entry, channels) in [
+            (yuv, Some(3)),
+            (yuva, Some(4)),
+            (huge, Some(3)),
+            (delta, Some(4)),
+            (sub, Some(3)),
+            (unknown, None),
+        ] {
+            let head = &entry[..entry.len().min(GeImage::HEADER_LEN)];
+            assert_eq!(Entry::probe(head).channels, channels);
+            assert_eq!(
+                Entry::probe(head).to_json()["channels"].as_u64(),
+                channels.map(|c| c as u64)
+            );
+        }
+    }
+
+    #[test]
+    fn main_unknown_fields_are_kept() {
+        let mut entry = Pgd::main(2, 2, 2).payload(&[], 6).build();
+        entry[4..12].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        entry[4 + 0x1a..4 + 0x1c].copy_from_slice(&0xabcdu16.to_le_bytes());
+        let header = GeImage::probe(&mut &entry[..]).unwrap();
+        assert_eq!(
+            header.to_string(),
+            "main 2x2 filter_type=2 size_orig=6 size_comp=0 \
+             unknown_04=0102030405060708 unknown_14=0000000000000000 unknown_1e=0xabcd"
+        );
+    }
+
+    #[test]
+    fn high_bit_depth_is_unsupported() {
+        for bpp in [48, 64] {
+            let e = GeImage::channels(bpp).unwrap_err();
+            assert!(e.downcast_ref::<Unsupported>().is_some());
+        }
+        assert!(
+            GeImage::channels(40)
+                .unwrap_err()
+                .downcast_ref::<Unsupported>()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn sub_flags_are_parsed() {
+        let mut entry = Pgd::sub(1, 1, 3)
+            .payload(&[0x00, 0x04, 0, 1, 2, 3], 4)
+            .build();
+        entry[4 + 0x2a..4 + 0x2c].copy_from_slice(&0x0102u16.to_le_bytes());
+        let header = GeImage::probe(&mut &entry[..]).unwrap();
+        assert_eq!(
+            header.to_string(),
+            "sub 1x1+0+0 channels=3 base= flags=0x0102 size_orig=4 size_comp=6"
+        );
+    }
+
+    #[test]
+    fn sub_size_must_match_dimensions() {
+        let images = HashMap::new();
+        let options = DecodeOptions::default();
+        let entry = Pgd::sub(1, 1, 3)
+            .payload(&[0x00, 0x04, 0, 1, 2, 3], 4)
+            .build();
+        let image = GeImage::decode_sub(&mut &entry[4..], &images, &options).unwrap();
+        assert_eq!(image.dimensions(), (1, 1));
+        let entry = Pgd::sub(1, 1, 3)
+            .payload(&[0x00, 0x05, 0, 1, 2, 3, 4], 5)
+            .build();
+        let e = GeImage::decode_sub(&mut &entry[4..], &images, &options).unwrap_err();
+        assert_eq!(e.to_string(), "sub data is 0x5 bytes but 1x1x3 needs 0x4");
+    }
+
+    #[test]
+    fn color_order_bgra_swaps_red_and_blue() {
+        let image = ImageBuffer::from_pixel(1, 1, Rgba([1, 2, 3, 4]));
+        let options = DecodeOptions::default();
+        assert_eq!(options.apply(&image).get_pixel(0, 0).0, [1, 2, 3, 4]);
+        let options = DecodeOptions {
+            bgra: true,
+            ..Default::default()
+        };
+        assert_eq!(options.apply(&image).get_pixel(0, 0).0, [3, 2, 1, 4]);
+    }
+
+    #[test]
+    fn image_cache_evicts_least_recently_used() {
+        let image = |value| ImageBuffer::from_pixel(2, 1, Rgba([value; 4]));
+        let hash = |value| blake3::hash(&[value]);
+        let mut cache = ImageCache::new(16).into_inner().unwrap();
+        cache.insert(hash(1), &image(1));
+        cache.insert(hash(2), &image(2));
+        assert_eq!(cache.get(&hash(1)), Some(image(1)));
+        cache.insert(hash(3), &image(3));
+        assert_eq!(cache.get(&hash(2)), None);
+        assert_eq!(cache.get(&hash(1)), Some(image(1)));
+        assert_eq!(cache.get(&hash(3)), Some(image(3)));
+        assert_eq!(cache.size, 16);
+        cache.insert(hash(4), &ImageBuffer::new(5, 1));
+        assert_eq!(cache.get(&hash(4)), None);
+        assert_eq!(ImageCache::new(0).lock().unwrap().get(&hash(1)), None);
+        for _ in 0..1000 {
+            cache.get(&hash(1));
+        }
+        assert!(cache.order.len() <= 2 * cache.images.len() + 16);
+        cache.insert(hash(5), &image(5));
+        assert_eq!(cache.get(&hash(3)), None);
+        assert_eq!(cache.get(&hash(1)), Some(image(1)));
+    }
+
+    #[test]
+    fn sub_bases_come_from_the_image_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DECODED: AtomicUsize = AtomicUsize::new(0);
+        let main = Pgd::delta(2, 3, &[0], &[1, 2, 3, 4, 5, 6]).build();
+        let sub = Pgd::sub(1, 1, 3)
+            .base("bg.pgd", 1, 0)
+            .data(&[0, 3, 2, 1])
+            .build();
+        let dir = TempDir::with_pac(&[(b"bg.pgd", &main), (b"ev.pgd", &sub)]);
+        let options = Options {
+            image_cache: 1 << 20,
+            ..Default::default()
+        };
+        let loader = AssetLoader::new("out", options)
+            .register_decoder(GeImage::MAIN_MAGIC, |_, _, _, _| {
+                DECODED.fetch_add(1, Ordering::Relaxed);
+                Ok(ImageBuffer::from_pixel(2, 1, Rgba([9, 9, 9, 0xff])))
+            })
+            .load(&dir.to_string_lossy())
+            .unwrap();
+        let pac = &loader.data[0];
+        let mut image = RgbaImage::new(0, 0);
+        for _ in 0..3 {
+            loader.decode_into(pac, "ev.pgd", &mut image).unwrap();
+        }
+        assert_eq!(DECODED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn file_limit_returns_slots_on_drop() {
+        let limit = FileLimit::new(1);
+        let exe = env::current_exe().unwrap();
+        let first = limit.open(&exe, 0).unwrap();
+        let second = limit.open(&exe, 0).unwrap();
+        assert_eq!(*limit.available.lock().unwrap(), 0);
+        drop(first);
+        assert!(limit.open(Path::new("/nonexistent/a.pac"), 0).is_err());
+        assert_eq!(*limit.available.lock().unwrap(), 1);
+        drop(second);
+        assert_eq!(*limit.available.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn normalize_newlines_only_for_text() {
+        assert!(is_text("選択肢\r\n\tend\r".as_bytes()));
+        assert!(!is_text(b"GE \0"));
+        assert!(!is_text(b""));
+        assert_eq!(normalize_newlines(b"a\r\nb\rc\n\r\r\n"), b"a\nb\nc\n\n\n");
+    }
+
+    #[test]
+    fn supported_modes_decode() {
+        for &mode in GeImage::supported_delta_modes() {
+            let entry = Pgd::delta(1, 3, &[0, mode], &[1, 2, 3, 4, 5, 6]).build();
+            assert!(GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).is_ok());
+        }
+        let entry = Pgd::main(2, 2, 5).payload(&[0x00, 0x00], 0).build();
+        assert!(!GeImage::supported_filters().contains(&5));
+        assert_eq!(
+            GeImage::unsupported(&mut &entry[..]).unwrap().as_deref(),
+            Some("filter_type 5")
+        );
+    }
+
+    #[test]
+    fn delta_filter_rejects_unknown_mode() {
+        let entry = Pgd::delta(1, 3, &[1, 7], &[0; 6]).build();
+        let e = GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).unwrap_err();
+        assert_eq!(e.to_string(), "unsupported delta mode 7 at row 1");
+    }
+
+    #[test]
+    fn contact_sheet_goes_through_the_sink() {
+        let main = Pgd::main(2, 2, 2).data(&[0; 12]).build();
+        let options = Options {
+            contact_sheet: Some("sheet.png".into()),
+            merge: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let files = extract(&[(b"a.pgd", &main)], options);
+        assert!(files["sheet.txt"].ends_with(b"data.pac/a.pgd\n"));
+        assert!(image::load_from_memory(&files["sheet.png"]).is_ok());
+    }
+
+    #[test]
+    fn save_keeps_the_callers_sink() {
+        let dir = TempDir::with_pac(&[(b"a.txt", b"first")]);
+        let out = dir.join("out");
+        let options = Options {
+            merge: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let sink = MemorySink::default();
+        let mut loader = AssetLoader::new(&out.to_string_lossy(), options)
+            .with_sink(sink.clone())
+            .load(&dir.to_string_lossy())
+            .unwrap();
+        loader.save().unwrap();
+        sink.0.lock().unwrap().clear();
+        loader.merged.borrow_mut().clear();
+        loader.save().unwrap();
+        assert_eq!(sink.files().into_keys().collect::<Vec<_>>(), ["a.txt"]);
+        assert!(!out.exists());
+        let options = Options {
+            zip: Some(dir.join("out.zip")),
+            merge: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let sink = MemorySink::default();
+        let mut loader = AssetLoader::new(&out.to_string_lossy(), options)
+            .with_sink(sink.clone())
+            .load(&dir.to_string_lossy())
+            .unwrap();
+        loader.save().unwrap();
+        assert!(sink.files().is_empty());
+        let zip = fs::read(dir.join("out.zip")).unwrap();
+        fs::remove_file(dir.join("out.zip")).unwrap();
+        loader.merged.borrow_mut().clear();
+        loader.save().unwrap();
+        assert_eq!(fs::read(dir.join("out.zip")).unwrap(), zip);
+        assert!(sink.files().is_empty());
+    }
+
+    #[test]
+    fn checksums_cover_image_payloads() {
+        let pixels = [1, 2, 3, 4, 5, 6];
+        let main = Pgd::delta(2, 3, &[0], &pixels).build();
+        let mut moved = Pgd::sub(1, 1, 3)
+            .base("bg.pgd", 1, 0)
+            .data(&[0, 3, 2, 1])
+            .build();
+        let sub = moved.clone();
+        moved[4] = 0;
+        let manifest = |entries: &[(&[u8], &[u8])]| {
+            let options = Options {
+                checksums: true,
+                merge: true,
+                quiet: true,
+                ..Default::default()
+            };
+            let files = extract(entries, options);
+            let manifest: serde_json::Value =
+                serde_json::from_slice(&files["manifest.json"]).unwrap();
+            let crc = |name: &str| {
+                let (_, entry) = manifest
+                    .as_object()
+                    .unwrap()
+                    .iter()
+                    .find(|(key, _)| key.ends_with(&format!("data.pac/{name}")))
+                    .unwrap();
+                entry["crc32"].as_str().unwrap().to_string()
+            };
+            [crc("a.txt"), crc("bg.pgd"), crc("ev.pgd")]
+        };
+        let crcs = manifest(&[(b"a.txt", b"first"), (b"bg.pgd", &main), (b"ev.pgd", &sub)]);
+        let mut delta = vec![0, 0, 0x18, 0, 0, 0, 0, 0, 0];
+        delta.extend(pixels);
+        let payload = common::stored(&delta);
+        assert_eq!(crcs[0], format!("{:08x}", crc32fast::hash(b"first")));
+        assert_eq!(crcs[1], format!("{:08x}", crc32fast::hash(&payload)));
+        let moved = manifest(&[
+            (b"a.txt", b"first"),
+            (b"bg.pgd", &main),
+            (b"ev.pgd", &moved),
+        ]);
+        assert_eq!(moved, crcs);
+    }
+
+    #[test]
+    fn fail_on_unsupported_overrides_fallback() {
+        let entry = Pgd::delta(1, 3, &[1, 7], &[0; 6]).build();
+        let save = |fail_on_unsupported| {
+            let options = Options {
+                fallback_raw: true,
+                fail_on_unsupported,
+                merge: true,
+                quiet: true,
+                ..Default::default()
+            };
+            try_extract(&[(b"bg.pgd", &entry)], options)
+        };
+        assert_eq!(
+            save(false).unwrap().into_keys().collect::<Vec<_>>(),
+            ["bg.pgd"]
+        );
+        let e = save(true).unwrap_err();
+        assert!(e.downcast_ref::<Unsupported>().is_some());
+    }
+
+    #[test]
+    fn verify_only_counts_unknown_magics() {
+        let main = Pgd::main(2, 2, 2).data(&[0; 12]).build();
+        let entries = [
+            (&b"a.pgd"[..], &b"XXXX"[..]),
+            (b"b.pgd", &main),
+            (b"c.pgd", b"YYYY"),
+        ];
+        let verify = |fail_on_unsupported| {
+            let options = Options {
+                verify_only: true,
+                fail_on_unsupported,
+                merge: true,
+                quiet: true,
+                ..Default::default()
+            };
+            try_extract(&entries, options).unwrap_err()
+        };
+        assert_eq!(verify(false).to_string(), "2 entries failed to decode");
+        assert!(verify(true).downcast_ref::<Unsupported>().is_some());
+    }
+
+    #[test]
+    fn delta_filter_mixed_modes() {
+        let pixels = [
+            10, 20, 30, 40, 50, 60, // mode 0
+            5, 6, 7, 1, 2, 3, // mode 1
+            1, 1, 1, 1, 1, 1, // mode 2
+            0, 0, 0, 1, 1, 1, // mode 4
+        ];
+        let entry = Pgd::delta(2, 3, &[0, 1, 2, 4], &pixels).build();
+        let image = GeImage::decode_main(&mut &entry[4..], &DecodeOptions::default()).unwrap();
+        let bgr = [
+            [10, 20, 30, 40, 50, 60],
+            [5, 6, 7, 4, 4, 4],
+            [4, 5, 6, 3, 3, 3],
+            [0, 0, 0, 0, 0, 0],
+        ];
+        for (y, row) in bgr.iter().enumerate() {
+            for (x, p) in row.chunks(3).enumerate() {
+                assert_eq!(
+                    image.get_pixel(x as u32, y as u32).0,
+                    [p[2], p[1], p[0], 0xff]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn from_reader_pacs_extract() {
+        let main = Pgd::delta(2, 3, &[0], &[1, 2, 3, 4, 5, 6]).build();
+        let sub = Pgd::sub(1, 1, 3)
+            .base("bg.pgd", 1, 0)
+            .data(&[0, 3, 2, 1])
+            .build();
+        let archive = pac_archive(&[(b"a.txt", b"first"), (b"bg.pgd", &main), (b"ev.pgd", &sub)]);
+        let pac = PacData::from_reader("data.pac", Cursor::new(archive)).unwrap();
+        let options = Options {
+            merge: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let sink = MemorySink::default();
+        let mut loader = AssetLoader::new("out", options)
+            .with_sink(sink.clone())
+            .with_pac(pac);
+        let mut image = RgbaImage::new(0, 0);
+        loader
+            .decode_into(&loader.data[0], "ev.pgd", &mut image)
+            .unwrap();
+        assert_eq!(image.dimensions(), (2, 1));
+        loader.save().unwrap();
+        assert_eq!(
+            sink.files().keys().collect::<Vec<_>>(),
+            ["a.txt", "bg.png", "ev.png"]
+        );
+    }
+
+    #[test]
+    fn from_reader_reads_entries() {
+        let archive = pac_archive(&[(b"a.txt", b"first"), (b"B.TXT", b"second")]);
+        let pac = PacData::from_reader("test.pac", Cursor::new(archive)).unwrap();
+        assert_eq!(pac.entry("a.txt").unwrap(), b"first");
+        assert_eq!(pac.entry("b.txt").unwrap(), b"second");
+        assert!(pac.entry("c.txt").is_err());
+    }
+
+    #[test]
+    fn directory_offset_field() {
+        let archive = pac_archive_at(0x20, &[(b"a.txt", b"first"), (b"b.txt", b"second")]);
+        assert!(archive.len() < PacLayout::LEGACY.directory as usize);
+        let pac = PacData::from_reader("test.pac", Cursor::new(archive)).unwrap();
+        assert_eq!(pac.entry("a.txt").unwrap(), b"first");
+        assert_eq!(pac.entry("b.txt").unwrap(), b"second");
+    }
+
+    #[test]
+    fn legacy_header_bytes_are_not_an_offset() {
+        let entries = [(&b"a.txt"[..], &b"first"[..]), (b"b.txt", b"second")];
+        let mut archive = pac_archive(&entries);
+        // header padding that happens to look like a directory offset: empty
+        // names at 0x20 and 0x7e0, and c.txt at 0x40 with its data inside
+        // the header
+        for directory in [0x20u32, 0x40, 0x7e0] {
+            archive[0x10..0x14].copy_from_slice(&directory.to_le_bytes());
+            archive[0x40..0x45].copy_from_slice(b"c.txt");
+            let pac = PacData::from_reader("test.pac", Cursor::new(archive.clone())).unwrap();
+            assert_eq!(pac.layout.directory, PacLayout::LEGACY.directory);
+            assert_eq!(pac.entry("a.txt").unwrap(), b"first");
+            assert_eq!(pac.entry("b.txt").unwrap(), b"second");
+        }
+    }
+
+    #[test]
+    fn names_stop_at_first_nul() {
+        let archive = pac_archive(&[(b"a.txt\0garbage", b"first"), (b"b.txt \t\x01", b"second")]);
+        let pac = PacData::from_reader("test.pac", Cursor::new(archive)).unwrap();
+        let mut names = pac
+            .metadata
+            .keys()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn sorted_entries() {
+        let archive = pac_archive(&[(b"b.txt", b"first"), (b"a.txt", b"2nd")]);
+        let pac = PacData::from_reader("test.pac", Cursor::new(archive)).unwrap();
+        let names = |order| {
+            pac.sorted(order)
+                .into_iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(EntryOrder::Archive), ["b.txt", "a.txt"]);
+        assert_eq!(names(EntryOrder::Name), ["a.txt", "b.txt"]);
+        assert_eq!(names(EntryOrder::Offset), ["b.txt", "a.txt"]);
+        assert_eq!(names(EntryOrder::Size), ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn duplicate_names_are_kept() {
+        let archive = pac_archive(&[
+            (b"a.pgd", b"first"),
+            (b"a.pgd", b"second"),
+            (b"a", b"third"),
+            (b"a", b"fourth"),
+        ]);
+        let pac = PacData::from_reader("test.pac", Cursor::new(archive)).unwrap();
+        assert_eq!(pac.entry("a.pgd").unwrap(), b"first");
+        assert_eq!(pac.entry("a.1.pgd").unwrap(), b"second");
+        assert_eq!(pac.entry("a").unwrap(), b"third");
+        assert_eq!(pac.entry("a.1").unwrap(), b"fourth");
+    }
+
+    #[test]
+    fn index_names_for_unusable_names() {
+        let archive = pac_archive(&[
+            (b"ok.txt", b"first"),
+            (b"\xff\xff.txt", b"second"),
+            (b"../up.dat", b"third"),
+        ]);
+        let pac = PacData::from_reader("test.pac", Cursor::new(archive)).unwrap();
+        let options = Options {
+            index_names: true,
+            ..Default::default()
+        };
+        let loader = AssetLoader::new("", options);
+        let names = pac
+            .order
+            .iter()
+            .map(|name| loader.output_name(&pac, name).into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["ok.txt", "entry_0001.txt", "entry_0002.dat"]);
+        assert_eq!(
+            pac.raw_names.values().collect::<Vec<_>>(),
+            [b"\xff\xff.txt"]
+        );
+    }
+
+    #[test]
+    fn preserve_case_output_names() {
+        let archive = pac_archive(&[(b"Mixed.TXT", b"first"), (b"MIXED.txt", b"second")]);
+        let pac = PacData::from_reader("test.pac", Cursor::new(archive)).unwrap();
+        assert_eq!(pac.entry("mixed.1.txt").unwrap(), b"second");
+        let options = Options {
+            preserve_case: true,
+            ..Default::default()
+        };
+        let loader = AssetLoader::new("", options);
+        assert_eq!(loader.output_name(&pac, "mixed.txt"), "Mixed.TXT");
+        assert_eq!(loader.output_name(&pac, "mixed.1.txt"), "MIXED.1.txt");
+        let path =
+            AssetLoader::entry_path(Path::new("out"), &loader.output_name(&pac, "mixed.txt"));
+        assert_eq!(path.unwrap(), Path::new("out/Mixed.TXT"));
+        let loader = AssetLoader::new("", Options::default());
+        assert_eq!(loader.output_name(&pac, "mixed.txt"), "mixed.txt");
+    }
+
+    #[test]
+    fn name_encodings() {
+        for (encoding, name, expected) in [
+            (encoding_rs::SHIFT_JIS, &b"\x82\xa0.TXT"[..], "\u{3042}.txt"),
+            (encoding_rs::GBK, &b"\xd6\xd0.TXT"[..], "\u{4e2d}.txt"),
+            (encoding_rs::UTF_8, &b"\xc3\xa9.TXT"[..], "\u{e9}.txt"),
+            (encoding_rs::WINDOWS_1252, &b"\xc9.TXT"[..], "\u{e9}.txt"),
+        ] {
+            let archive = pac_archive(&[(name, b"data")]);
+            let pac = PacData::new("test.pac", encoding)
+                .read_directory(&mut Cursor::new(archive))
+                .unwrap();
+            assert!(pac.metadata.keys().any(|name| **name == expected));
+        }
+    }
+
+    #[test]
+    fn split_set_reads_next_part() {
+        let dir = TempDir::new();
+        let archive = pac_archive(&[(b"a.txt", b"first"), (b"b.txt", b"second")]);
+        let (primary, part) = archive.split_at(archive.len() - 6);
+        fs::write(dir.join("data.pac"), primary).unwrap();
+        fs::write(dir.join("data_01.pac"), part).unwrap();
+        let path = dir.join("data.pac");
+        let pac = PacData::open(
+            &path.to_string_lossy(),
+            false,
+            encoding_rs::SHIFT_JIS,
+            0,
+            FileLimit::new(2),
+        )
+        .unwrap();
+        assert!(PacData::is_part(&dir.join("data_01.pac")));
+        assert_eq!(pac.entry("a.txt").unwrap(), b"first");
+        assert_eq!(pac.entry("b.txt").unwrap(), b"second");
+    }
+
+    #[test]
+    fn split_set_decodes_images_in_part() {
+        let dir = TempDir::new();
+        let main = Pgd::delta(2, 3, &[0], &[1, 2, 3, 4, 5, 6]).build();
+        let sub = Pgd::sub(1, 1, 3)
+            .base("bg.pgd", 1, 0)
+            .data(&[0, 3, 2, 1])
+            .build();
+        let archive = pac_archive(&[(b"a.txt", b"first"), (b"bg.pgd", &main), (b"ev.pgd", &sub)]);
+        let (primary, part) = archive.split_at(archive.len() - main.len() - sub.len());
+        fs::write(dir.join("data.pac"), primary).unwrap();
+        fs::write(dir.join("data_01.pac"), part).unwrap();
+        let options = Options {
+            sidecar: true,
+            merge: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let sink = MemorySink::default();
+        AssetLoader::new("out", options)
+            .with_sink(sink.clone())
+            .load(&dir.to_string_lossy())
+            .unwrap()
+            .save()
+            .unwrap();
+        let files = sink.files();
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            ["a.txt", "bg.png", "bg.png.json", "ev.png", "ev.png.json"]
+        );
+        let sidecar = |name| serde_json::from_slice::<serde_json::Value>(&files[name]).unwrap();
+        let main = sidecar("bg.png.json");
+        assert_eq!(
+            (main["filter_type"].as_u64(), main["channels"].as_u64()),
+            (Some(3), Some(3))
+        );
+        let sub = sidecar("ev.png.json");
+        assert_eq!(
+            (sub["base"].as_str(), sub["channels"].as_u64()),
+            (Some("bg.pgd"), Some(3))
+        );
+    }
+
+    #[test]
+    fn palette_output_when_few_colors() {
+        let options = Options {
+            palette_output: true,
+            ..Default::default()
+        };
+        let loader = AssetLoader::new("", options);
+        let image = ImageBuffer::from_fn(4, 2, |x, _| Rgba([x as u8, 0, 0, 0xff - x as u8]));
+        let data = loader.encode_image(&image).unwrap();
+        let mut reader = png::Decoder::new(Cursor::new(data)).read_info().unwrap();
+        assert_eq!(reader.info().color_type, png::ColorType::Indexed);
+        assert_eq!(reader.info().palette.as_ref().unwrap().len(), 12);
+        let mut indices = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut indices).unwrap();
+        assert_eq!(indices, [0, 1, 2, 3, 0, 1, 2, 3]);
+
+        let image = ImageBuffer::from_fn(17, 17, |x, y| Rgba([x as u8, y as u8, 0, 0xff]));
+        assert!(OutputFormat::encode_indexed(&image).unwrap().is_none());
+    }
+
+    #[test]
+    fn psd_layers() {
+        let base = ImageBuffer::from_pixel(4, 3, Rgba([1, 2, 3, 0xff]));
+        let layer = ImageBuffer::from_pixel(2, 1, Rgba([4, 5, 6, 0xff]));
+        let data = OutputFormat::encode_psd("a.pgd", &base, &[("a_1.pgd".into(), 1, 2, layer)]);
+        assert_eq!(&data[..4], b"8BPS");
+        assert_eq!(&data[14..22], [0, 0, 0, 3, 0, 0, 0, 4]);
+        let section = u32::from_be_bytes(data[34..38].try_into().unwrap()) as usize;
+        assert_eq!(data.len(), 38 + section + 2 + 4 * 12);
+        assert_eq!(&data[42..44], [0, 2]);
+        assert_eq!(&data[data.len() - 24..data.len() - 20], [3; 4]);
+    }
+
+    #[test]
+    fn retry_only_transient_errors() {
+        let mut attempts = 0;
+        let result = retry(2, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(io::ErrorKind::Interrupted.into()),
+                2 => Err(io::ErrorKind::TimedOut.into()),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        let mut attempts = 0;
+        let result = retry::<()>(2, || {
+            attempts += 1;
+            Err(io::ErrorKind::NotFound.into())
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn merge_dedups_and_suffixes_conflicts() {
+        let dir = TempDir::new();
+        let options = Options {
+            merge: true,
+            ..Default::default()
+        };
+        let loader = AssetLoader::new(&dir.to_string_lossy(), options);
+        let path = dir.join("bg.png");
+        loader.write_file(&path, b"same").unwrap();
+        loader.write_file(&path, b"same").unwrap();
+        loader.write_file(&path, b"other").unwrap();
+        loader.write_file(&path, b"third").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"same");
+        assert_eq!(fs::read(dir.join("bg.1.png")).unwrap(), b"other");
+        assert_eq!(fs::read(dir.join("bg.2.png")).unwrap(), b"third");
+        assert_eq!(loader.conflicts.borrow().len(), 2);
+    }
+
+    #[test]
+    fn memory_sink_collects_relative_paths() {
+        let sink = MemorySink::default();
+        let loader = AssetLoader::new("out", Options::default()).with_sink(sink.clone());
+        loader
+            .write_file(Path::new("out/sys/bg.png"), b"data")
+            .unwrap();
+        assert_eq!(
+            sink.files().into_iter().collect::<Vec<_>>(),
+            [("sys/bg.png".to_string(), b"data".to_vec())]
+        );
+    }
+
+    #[test]
+    fn dedup_hardlink_reruns_leave_links_intact() {
+        let dir = TempDir::new();
+        let options = Options {
+            dedup_hardlink: true,
+            ..Default::default()
+        };
+        let run = |a: &[u8], b: &[u8]| {
+            let mut sink = FsSink::new(&dir.to_string_lossy(), &options);
+            sink.write("a.bin", a).unwrap();
+            sink.write("b.bin", b).unwrap();
+        };
+        run(b"same", b"same");
+        run(b"same", b"changed");
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), b"same");
+        assert_eq!(fs::read(dir.join("b.bin")).unwrap(), b"changed");
+    }
+
+    #[test]
+    fn composite_names() {
+        assert_eq!(
+            AssetLoader::composite_name("bg01.pgd", "ev01a.pgd"),
+            "bg01__ev01a.pgd"
+        );
+        assert_eq!(
+            AssetLoader::composite_name("cg/bg.pgd", "ev/a.pgd"),
+            "ev/bg__a.pgd"
+        );
+    }
+
+    #[test]
+    fn replace_entry_shifts_later_offsets() {
+        let entries: [(&[u8], &[u8]); 3] =
+            [(b"a.txt", b"first"), (b"b.txt", b"2"), (b"c.txt", b"third")];
+        let dir = TempDir::with_pac(&entries);
+        let archive = pac_archive(&entries);
+        let loader = AssetLoader::new("", Options::default());
+        let pac = loader
+            .open(&dir.join("data.pac").to_string_lossy())
+            .unwrap();
+        let out = dir.join("new.pac");
+        loader
+            .replace_entry(&pac, "B.TXT", b"second", &out.to_string_lossy())
+            .unwrap();
+        let replaced = fs::read(&out).unwrap();
+        let pac = PacData::from_reader("new.pac", Cursor::new(replaced.clone())).unwrap();
+        assert_eq!(pac.entry("a.txt").unwrap(), b"first");
+        assert_eq!(pac.entry("b.txt").unwrap(), b"second");
+        assert_eq!(pac.entry("c.txt").unwrap(), b"third");
+        let u32_at = |i: usize| u32::from_le_bytes(archive[i..i + 4].try_into().unwrap());
+        let b = PacLayout::LEGACY.directory as usize + 40 + 32;
+        let c = b + 40;
+        let mut expected = archive.clone();
+        expected[b..b + 4].copy_from_slice(&6u32.to_le_bytes());
+        expected[c + 4..c + 8].copy_from_slice(&(u32_at(c + 4) + 5).to_le_bytes());
+        let offset = u32_at(b + 4) as usize;
+        expected.splice(offset..offset + 1, *b"second");
+        assert_eq!(replaced, expected);
+    }
+
+    #[test]
+    fn trim_bounds_skip_opaque_and_empty() {
+        let mut image = RgbaImage::new(4, 3);
+        assert_eq!(AssetLoader::trim_bounds(&image), None);
+        image.put_pixel(1, 1, Rgba([1, 2, 3, 4]));
+        image.put_pixel(2, 1, Rgba([1, 2, 3, 0xff]));
+        assert_eq!(AssetLoader::trim_bounds(&image), Some((1, 1, 2, 1)));
+        let image = RgbaImage::from_pixel(4, 3, Rgba([0, 0, 0, 0xff]));
+        assert_eq!(AssetLoader::trim_bounds(&image), None);
+    }
+
+    #[test]
+    fn repair_recomputes_offsets() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("src")).unwrap();
+        let mut archive = pac_archive(&[(b"A.txt", b"first"), (b"b.txt", b"second")]);
+        let record = PacLayout::LEGACY.directory as usize + PacLayout::LEGACY.name_len + 8;
+        let field = record + PacLayout::LEGACY.name_len + 4;
+        archive[field..field + 4].copy_from_slice(&0xffffu32.to_le_bytes());
+        fs::write(dir.join("broken.pac"), &archive).unwrap();
+        fs::write(dir.join("src/a.txt"), b"first").unwrap();
+        fs::write(dir.join("src/b.txt"), b"second!").unwrap();
+        let loader = AssetLoader::new("", Options::default());
+        let broken = loader
+            .open(&dir.join("broken.pac").to_string_lossy())
+            .unwrap();
+        let out = dir.join("fixed.pac");
+        loader
+            .repair(
+                &broken,
+                &dir.join("src").to_string_lossy(),
+                &out.to_string_lossy(),
+            )
+            .unwrap();
+        let fixed = fs::read(&out).unwrap();
+        assert_eq!(fixed[..record], archive[..record]);
+        let pac = PacData::from_reader("fixed.pac", Cursor::new(fixed)).unwrap();
+        assert_eq!(pac.entry("a.txt").unwrap(), b"first");
+        assert_eq!(pac.entry("b.txt").unwrap(), b"second!");
+        assert_eq!(
+            pac.order
+                .iter()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>(),
+            ["a.txt", "b.txt"]
+        );
+
+        let options = Options {
+            align: Some(0x100),
+            ..Default::default()
+        };
+        AssetLoader::new("", options)
+            .repair(
+                &broken,
+                &dir.join("src").to_string_lossy(),
+                &out.to_string_lossy(),
+            )
+            .unwrap();
+        let pac = PacData::from_reader("fixed.pac", Cursor::new(fs::read(&out).unwrap())).unwrap();
+        assert!(
+            pac.metadata
+                .values()
+                .all(|&(offset, _)| offset % 0x100 == 0)
+        );
+        assert_eq!(pac.entry("a.txt").unwrap(), b"first");
+        assert_eq!(pac.entry("b.txt").unwrap(), b"second!");
+    }
+
+    #[test]
+    fn entry_path_splits_backslashes() {
+        let out = Path::new("out");
+        assert_eq!(
+            AssetLoader::entry_path(out, r"cg\ev01.pgd").unwrap(),
+            out.join("cg").join("ev01.pgd")
+        );
+        assert_eq!(
+            AssetLoader::entry_path(out, r"cg\.\sub/ev02.pgd").unwrap(),
+            out.join("cg").join("sub").join("ev02.pgd")
+        );
+        for name in [r"..\..\evil.txt", r"cg\..\..\evil.txt"] {
+            assert!(AssetLoader::entry_path(out, name).is_err());
+        }
+        let options = Options {
+            merge: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let files = extract(&[(br"cg\ev01.txt", b"first")], options);
+        assert_eq!(files.into_keys().collect::<Vec<_>>(), ["cg/ev01.txt"]);
+    }
+
+    #[test]
+    fn auto_decompress_reads_gzipped_pacs() {
+        use flate2::write::{GzEncoder, ZlibEncoder};
+        let dir = TempDir::new();
+        let archive = pac_archive(&[(b"a.txt", b"first"), (b"b.txt", b"second")]);
+        let mut gzip = GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(&archive).unwrap();
+        let mut zlib = ZlibEncoder::new(vec![], flate2::Compression::default());
+        zlib.write_all(&archive).unwrap();
+        let load = |auto_decompress| {
+            let options = Options {
+                auto_decompress,
+                merge: true,
+                quiet: true,
+                ..Default::default()
+            };
+            let sink = MemorySink::default();
+            AssetLoader::new("out", options)
+                .with_sink(sink.clone())
+                .load(&dir.to_string_lossy())?
+                .save()
+                .map(|_| sink.files())
+        };
+        for pac in [gzip.finish().unwrap(), zlib.finish().unwrap()] {
+            fs::write(dir.join("data.pac"), pac).unwrap();
+            let files = load(true).unwrap();
+            assert_eq!(files["a.txt"], b"first");
+            assert_eq!(files["b.txt"], b"second");
+            assert!(load(false).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn resume_skips_logged_entries() {
+        let dir = TempDir::new();
+        let out = dir.join("out");
+        let save = |entries: &[(&[u8], &[u8])]| {
+            fs::write(dir.join("data.pac"), pac_archive(entries)).unwrap();
+            let options = Options {
+                resume: true,
+                merge: true,
+                quiet: true,
+                ..Default::default()
+            };
+            let sink = MemorySink::default();
+            AssetLoader::new(&out.to_string_lossy(), options)
+                .with_sink(sink.clone())
+                .load(&dir.to_string_lossy())
+                .unwrap()
+                .save()
+                .unwrap();
+            sink.files().into_keys().collect::<Vec<_>>()
+        };
+        let log = out.join(ResumeLog::NAME);
+        assert_eq!(
+            save(&[(b"a.txt", b"1"), (b"b.txt", b"2")]),
+            ["a.txt", "b.txt"]
+        );
+        // a crash while writing b.txt's line leaves it torn
+        let mut lines = fs::read_to_string(&log).unwrap();
+        lines.truncate(lines.len() - 4);
+        fs::write(&log, &lines).unwrap();
+        let entries = [(b"a.txt", b"1"), (b"b.txt", b"2"), (b"c.txt", b"3")];
+        let entries = entries.map(|(name, data)| (&name[..], &data[..]));
+        assert_eq!(save(&entries), ["b.txt", "c.txt"]);
+        assert_eq!(save(&entries), Vec::<String>::new());
+        let lines = fs::read_to_string(&log).unwrap();
+        let logged = |line| serde_json::from_str::<(String, String)>(line).ok();
+        assert_eq!(lines.lines().count(), 4);
+        assert_eq!(lines.lines().filter_map(logged).count(), 3);
+        let pac = logged(lines.lines().next().unwrap()).unwrap().0;
+        let resume = ResumeLog::open(&out).unwrap();
+        assert!(
+            ["a.txt", "b.txt", "c.txt"]
+                .iter()
+                .all(|name| resume.done(&pac, name))
+        );
+    }
+
+    #[test]
+    fn legacy_directory_layout() {
+        // count 2, no directory offset field, records of 32-byte name,
+        // u32 size and u32 offset from 0x804
+        let mut archive = b"PAC \0\0\0\0\x02\0\0\0\0\0\0\0".to_vec();
+        archive.resize(0x804, 0);
+        for (name, size, offset) in [(&b"a.txt"[..], 5u32, 0x854u32), (b"b.pgd", 6, 0x859)] {
+            let mut record = name.to_vec();
+            record.resize(32, 0);
+            archive.extend(record);
+            archive.extend(size.to_le_bytes());
+            archive.extend(offset.to_le_bytes());
+        }
+        archive.extend(b"firstsecond");
+        let pac = PacData::from_reader("test.pac", Cursor::new(archive.clone())).unwrap();
+        assert_eq!((pac.layout.directory, pac.layout.name_len), (0x0804, 32));
+        let names = pac
+            .order
+            .iter()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.txt", "b.pgd"]);
+        assert_eq!(pac.metadata[&pac.order[0]], (0x854, 5));
+        assert_eq!(pac.metadata[&pac.order[1]], (0x859, 6));
+        assert_eq!(pac.entry("a.txt").unwrap(), b"first");
+        assert_eq!(pac.entry("b.pgd").unwrap(), b"second");
+        assert_eq!(
+            archive,
+            pac_archive(&[(b"a.txt", b"first"), (b"b.pgd", b"second")])
+        );
+    }
+
+    #[test]
+    fn zero_dimensions_are_an_error() {
+        let options = DecodeOptions::default();
+        for filter_type in [2, 3] {
+            let entry = Pgd::main(0, 2, filter_type).data(&[0; 8]).build();
+            let e = GeImage::decode_main(&mut &entry[4..], &options).unwrap_err();
+            assert_eq!(e.to_string(), "invalid image dimensions: 0x2");
+        }
+        let entry = Pgd::sub(1, 0, 3).build();
+        let e = GeImage::decode_sub(&mut &entry[4..], &HashMap::new(), &options).unwrap_err();
+        assert_eq!(e.to_string(), "invalid image dimensions: 1x0");
+    }
+
+    #[test]
+    fn odd_yuv_dimensions_are_an_error() {
+        let options = DecodeOptions::default();
+        for (width, height) in [(3, 2), (2, 3), (3, 3)] {
+            let entry = Pgd::main(width, height, 2).data(&[0x80; 18]).build();
+            let e = GeImage::decode_main(&mut &entry[4..], &options).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("filter_type 2 requires even dimensions, got {width}x{height}")
+            );
+            let region = GeImage::decode_main_region(&mut &entry[4..], (0, 0, 1, 1), &options);
+            assert_eq!(region.unwrap_err().to_string(), e.to_string());
+            let mut data = vec![];
+            let mut context = DecodeContext::default();
+            assert!(
+                GeImage::stream_main(&mut &entry[4..], &options, &mut context, &mut data).is_err()
+            );
+        }
+        let entry = Pgd::delta(3, 3, &[0, 1, 2], &[0x80; 27]).build();
+        let image = GeImage::decode_main(&mut &entry[4..], &options).unwrap();
+        assert_eq!(image.dimensions(), (3, 3));
+    }
+
+    #[test]
+    fn baseless_sub_decodes_on_blank_canvas() {
+        let images = HashMap::new();
+        for base in ["", "  "] {
+            let entry = Pgd::sub(2, 1, 3)
+                .base(base, 5, 7)
+                .data(&[0, 3, 2, 1, 6, 5, 4])
+                .build();
+            let image = GeImage::decode_sub(&mut &entry[4..], &images, &DecodeOptions::default());
+            let image = image.unwrap();
+            assert_eq!(image.dimensions(), (2, 1));
+            assert_eq!(image.get_pixel(0, 0).0[..3], [1, 2, 3]);
+            assert_eq!(image.get_pixel(1, 0).0[..3], [4, 5, 6]);
+        }
+        let sub = Pgd::sub(1, 1, 3).data(&[0, 3, 2, 1]).build();
+        let options = Options {
+            merge: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let files = extract(&[(b"ev.pgd", &sub)], options);
+        let image = image::load_from_memory(&files["ev.png"])
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(image.get_pixel(0, 0).0[..3], [1, 2, 3]);
+    }
+
+    #[test]
+    fn qoi_round_trip() {
+        let image = ImageBuffer::from_fn(3, 2, |x, y| Rgba([x as u8, y as u8, 0x80, 0x40]));
+        let options = Options {
+            format: OutputFormat::Qoi,
+            ..Default::default()
+        };
+        let data = AssetLoader::new("", options).encode_image(&image).unwrap();
+        let decoded = image::load_from_memory_with_format(&data, ImageFormat::Qoi).unwrap();
+        assert_eq!(decoded.to_rgba8(), image);
+    }
+
+    #[test]
+    fn opaque_images_round_trip_through_rgb() {
+        let options = Options {
+            rgb_when_opaque: true,
+            ..Default::default()
+        };
+        let loader = AssetLoader::new("", options);
+        let opaque = ImageBuffer::from_fn(3, 2, |x, y| Rgba([x as u8, y as u8, 0x80, 0xff]));
+        let translucent = ImageBuffer::from_fn(3, 2, |x, y| Rgba([x as u8, y as u8, 0x80, 0x40]));
+        for (image, color) in [
+            (opaque, image::ColorType::Rgb8),
+            (translucent, image::ColorType::Rgba8),
+        ] {
+            let data = loader.encode_image(&image).unwrap();
+            let decoded = image::load_from_memory(&data).unwrap();
+            assert_eq!(decoded.color(), color);
+            assert_eq!(decoded.to_rgba8(), image);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Builders for synthetic PGD entries and PAC archives, shared by the unit
+//! tests in src/lib.rs and the integration tests.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use cratri_unpac::{AssetLoader, MemorySink, Options};
+
+/// Stores `data` as literal runs, eight to a control byte.
+pub fn stored(data: &[u8]) -> Vec<u8> {
+    let mut payload = vec![];
+    for tokens in data.chunks(0xff * 8) {
+        payload.push(0x00);
+        for run in tokens.chunks(0xff) {
+            payload.push(run.len() as u8);
+            payload.extend(run);
+        }
+    }
+    payload
+}
+
+/// Builds a main or sub entry around uncompressed `data`.
+pub struct Pgd {
+    magic: [u8; 4],
+    width: u32,
+    height: u32,
+    filter_type: u16,
+    channels: u16,
+    x: u16,
+    y: u16,
+    base: &'static str,
+    data: Vec<u8>,
+    payload: Option<(Vec<u8>, u32)>,
+}
+
+impl Pgd {
+    pub fn main(width: u32, height: u32, filter_type: u16) -> Self {
+        Self {
+            magic: *b"GE \0",
+            width,
+            height,
+            filter_type,
+            channels: 3,
+            x: 0,
+            y: 0,
+            base: "",
+            data: vec![],
+            payload: None,
+        }
+    }
+
+    pub fn sub(width: u32, height: u32, channels: u16) -> Self {
+        Self {
+            magic: *b"PGD3",
+            channels,
+            ..Self::main(width, height, 3)
+        }
+    }
+
+    /// A filter_type 3 main: delta header, one mode per row, then pixels.
+    pub fn delta(width: u32, channels: u8, deltas: &[u8], pixels: &[u8]) -> Self {
+        let mut data = vec![0, 0, channels << 3, 0, 0, 0, 0, 0];
+        data.extend(deltas);
+        data.extend(pixels);
+        Self::main(width, deltas.len() as u32, 3).data(&data)
+    }
+
+    pub fn base(self, base: &'static str, x: u16, y: u16) -> Self {
+        Self { base, x, y, ..self }
+    }
+
+    pub fn data(self, data: &[u8]) -> Self {
+        Self {
+            data: data.to_vec(),
+            ..self
+        }
+    }
+
+    /// Uses an already compressed `payload` and `size_orig` as given.
+    pub fn payload(self, payload: &[u8], size_orig: u32) -> Self {
+        Self {
+            payload: Some((payload.to_vec(), size_orig)),
+            ..self
+        }
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut entry = self.magic.to_vec();
+        if self.magic == *b"GE \0" {
+            entry.extend([0; 8]);
+            entry.extend(self.width.to_le_bytes());
+            entry.extend(self.height.to_le_bytes());
+            entry.extend([0; 8]);
+            entry.extend(self.filter_type.to_le_bytes());
+            entry.extend([0; 2]);
+        } else {
+            for field in [self.x, self.y, self.width as u16, self.height as u16] {
+                entry.extend(field.to_le_bytes());
+            }
+            entry.extend((self.channels << 3).to_le_bytes());
+            let mut base = self.base.as_bytes().to_vec();
+            base.resize(0x22, 0);
+            entry.extend(base);
+        }
+        let (payload, size_orig) = match &self.payload {
+            Some((payload, size_orig)) => (payload.clone(), *size_orig),
+            None => (stored(&self.data), self.data.len() as u32),
+        };
+        entry.extend(size_orig.to_le_bytes());
+        entry.extend((payload.len() as u32).to_le_bytes());
+        entry.extend(payload);
+        entry
+    }
+}
+
+/// A PAC with the legacy directory at 0x804.
+pub fn pac_archive(entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+    pac_archive_at(0x804, entries)
+}
+
+/// A PAC whose directory starts at `directory`, recorded at 0x10 unless it
+/// is the legacy 0x804.
+pub fn pac_archive_at(directory: u32, entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+    let mut pac = b"PAC ".to_vec();
+    pac.resize(8, 0);
+    pac.extend((entries.len() as u64).to_le_bytes());
+    if directory != 0x804 {
+        pac.extend(directory.to_le_bytes());
+    }
+    pac.resize(directory as usize, 0);
+    let mut offset = pac.len() + entries.len() * 40;
+    for (name, data) in entries {
+        let mut record = name.to_vec();
+        record.resize(32, 0);
+        pac.extend(record);
+        pac.extend((data.len() as u32).to_le_bytes());
+        pac.extend((offset as u32).to_le_bytes());
+        offset += data.len();
+    }
+    for (_, data) in entries {
+        pac.extend(*data);
+    }
+    pac
+}
+
+/// A fresh directory under the system temp dir, removed again on drop so that
+/// failing tests do not leave it behind.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let index = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("cratri_unpac-{}-{index}", process::id()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    /// A directory holding `entries` as data.pac.
+    pub fn with_pac(entries: &[(&[u8], &[u8])]) -> Self {
+        let dir = Self::new();
+        fs::write(dir.join("data.pac"), pac_archive(entries)).unwrap();
+        dir
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Saves `entries`, packed as data.pac, with `options` and returns the files
+/// written by path relative to the output.
+pub fn extract(entries: &[(&[u8], &[u8])], options: Options) -> BTreeMap<String, Vec<u8>> {
+    try_extract(entries, options).unwrap()
+}
+
+pub fn try_extract(
+    entries: &[(&[u8], &[u8])],
+    options: Options,
+) -> anyhow::Result<BTreeMap<String, Vec<u8>>> {
+    let dir = TempDir::with_pac(entries);
+    let sink = MemorySink::default();
+    AssetLoader::new("out", options)
+        .with_sink(sink.clone())
+        .load(&dir.to_string_lossy())?
+        .save()?;
+    Ok(sink.files())
+}
@@ -0,0 +1,52 @@
+use cratri_unpac::decompress;
+
+fn golden(input: &[u8], expected: &[u8]) {
+    assert_eq!(decompress(input, expected.len()).unwrap(), expected);
+}
+
+#[test]
+fn literal_runs() {
+    golden(&[0x00, 3, b'a', b'b', b'c'], b"abc");
+    golden(&[0x00, 3, b'a', b'b', b'c', 2, b'd', b'e'], b"abcde");
+}
+
+#[test]
+fn short_match() {
+    // 0x0028: look-behind 2, length (0 & 7) + 4, overlapping the output
+    golden(&[0x02, 2, b'a', b'b', 0x28, 0x00], b"ababab");
+    // 0x001f: look-behind 1, length (7 & 7) + 4, repeating the last byte
+    golden(&[0x02, 1, b'x', 0x1f, 0x00], b"xxxxxxxxxxxx");
+}
+
+#[test]
+fn long_match() {
+    // 0x003006: look-behind 3, length (1 + 1) << 2 | 2
+    golden(
+        &[0x02, 3, b'x', b'y', b'z', 0x30, 0x00, 0x06],
+        b"xyzxyzxyzxyzx",
+    );
+    // 0x0017ff: look-behind 1, the longest length (0x1ff + 1) << 2 | 3
+    golden(&[0x02, 1, b'a', 0x17, 0x00, 0xff], &[b'a'; 0x804]);
+}
+
+#[test]
+fn control_byte_spans_eight_tokens() {
+    let mut input = vec![0x00];
+    for digit in b'0'..b'8' {
+        input.extend([1, digit]);
+    }
+    input.extend([0x01, 0x88, 0x00]);
+    golden(&input, b"012345670123");
+}
+
+#[test]
+fn output_stops_at_size_orig() {
+    let input = [0x02, 2, b'a', b'b', 0x28, 0x00];
+    assert_eq!(decompress(&input, 5).unwrap(), b"ababa");
+    assert!(decompress(&input, 8).is_err());
+}
+
+#[test]
+fn back_reference_before_start_is_an_error() {
+    assert!(decompress(&[0x01, 0x28, 0x00], 4).is_err());
+}
@@ -0,0 +1,55 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use cratri_unpac::{AssetLoader, Options};
+
+mod common;
+
+use common::{Pgd, TempDir, pac_archive};
+
+fn pixels(path: &Path) -> Vec<[u8; 4]> {
+    image::load_from_memory(&fs::read(path).unwrap())
+        .unwrap()
+        .to_rgba8()
+        .pixels()
+        .map(|p| p.0)
+        .collect()
+}
+
+#[test]
+fn load_and_save_synthetic_pac() {
+    let dir = TempDir::new();
+    fs::create_dir(dir.join("in")).unwrap();
+    // 2x1 BGR, delta header then one mode-0 row
+    let main = Pgd::delta(2, 3, &[0], &[1, 2, 3, 4, 5, 6]).build();
+    // 1x1 BGR at x = 1, XORed onto the base
+    let sub = Pgd::sub(1, 1, 3)
+        .base("bg.pgd", 1, 0)
+        .data(&[0, 0x10, 0x20, 0x30])
+        .build();
+    let archive = pac_archive(&[
+        (b"readme.txt", b"hello"),
+        (b"bg.pgd", &main),
+        (b"ev.pgd", &sub),
+    ]);
+    fs::write(dir.join("in/data.pac"), archive).unwrap();
+    env::set_current_dir(&*dir).unwrap();
+
+    AssetLoader::new("out", Options::default())
+        .load("in")
+        .unwrap()
+        .save()
+        .unwrap();
+
+    let out = dir.join("out/in/data.pac");
+    assert_eq!(fs::read(out.join("readme.txt")).unwrap(), b"hello");
+    assert_eq!(
+        pixels(&out.join("bg.png")),
+        [[3, 2, 1, 0xff], [6, 5, 4, 0xff]]
+    );
+    assert_eq!(
+        pixels(&out.join("ev.png")),
+        [[3, 2, 1, 0xff], [0x36, 0x25, 0x14, 0xff]]
+    );
+}